@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{MuuzikaError, MuuzikaResult};
+use crate::lobby;
+use crate::messages::ServerMessage;
+use crate::rooms::{BroadcastTarget, ClientMetadata, Player, RoomCode, Username};
+use crate::state::{State, WrappedRoom};
+use crate::ws;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReassignedLeader {
+    pub room_code: RoomCode,
+    pub previous: Username,
+    pub leader: Username,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GcReport {
+    pub rooms_scanned: usize,
+    pub leaders_reassigned: Vec<ReassignedLeader>,
+    pub cleanups_scheduled: Vec<RoomCode>,
+}
+
+// Operator recovery tool for rooms left inconsistent by past bugs: a leader that isn't
+// one of the players gets replaced, and empty rooms nobody is cleaning up get cleaned up
+pub async fn collect_garbage(state: &State) -> GcReport {
+    const LOG_TARGET: &str = "muuzika::admin::collect_garbage";
+
+    let wrapped_rooms: Vec<WrappedRoom> = state.rooms.read().await.values().cloned().collect();
+    let mut report = GcReport {
+        rooms_scanned: wrapped_rooms.len(),
+        ..GcReport::default()
+    };
+
+    for wrapped_room in wrapped_rooms {
+        let needs_cleanup = {
+            let mut room = wrapped_room.write().await;
+
+            if !room.players.is_empty() && !room.players.contains_key(&room.leader) {
+                let previous = room.leader.clone();
+                room.elect_leader();
+                log::warn!(target: LOG_TARGET, "Leader \"{}\" of room {} was not in the room, \"{}\" is the new leader", previous, room.code, room.leader);
+                let _ = room.send(ServerMessage::LeaderChanged(room.leader.clone()));
+                report.leaders_reassigned.push(ReassignedLeader {
+                    room_code: room.code.clone(),
+                    previous,
+                    leader: room.leader.clone(),
+                });
+            }
+
+            // A sender whose receiver is gone belongs to a cleanup that already ran
+            let cleanup_pending = room
+                .cancel_cleanup
+                .as_ref()
+                .is_some_and(|tx| !tx.is_closed());
+            room.players.is_empty() && !cleanup_pending && !room.cleaning_up
+        };
+
+        if needs_cleanup {
+            let room_code = wrapped_room.read().await.code.clone();
+            log::warn!(target: LOG_TARGET, "Room {} is empty but had no cleanup scheduled", room_code);
+            lobby::schedule_room_cleanup(state.clone(), wrapped_room).await;
+            report.cleanups_scheduled.push(room_code);
+        }
+    }
+
+    report
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AnnouncementRequest {
+    pub text: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AnnouncementResponse {
+    pub players_reached: usize,
+}
+
+pub async fn announce(
+    state: &State,
+    request: &AnnouncementRequest,
+) -> MuuzikaResult<AnnouncementResponse> {
+    const LOG_TARGET: &str = "muuzika::admin::announce";
+
+    let message = ws::make_message(
+        ServerMessage::Announcement {
+            text: request.text.clone(),
+        },
+        None,
+    )?;
+
+    // Each room is only locked for its own broadcast, never the whole map at once
+    let wrapped_rooms: Vec<WrappedRoom> = state.rooms.read().await.values().cloned().collect();
+    let mut players_reached = 0;
+    for wrapped_room in wrapped_rooms {
+        players_reached += wrapped_room
+            .read()
+            .await
+            .broadcast_serialized(&message, BroadcastTarget::All);
+    }
+
+    log::info!(target: LOG_TARGET, "Announcement reached {} players: {}", players_reached, request.text);
+    Ok(AnnouncementResponse { players_reached })
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminPlayerDto {
+    pub username: Username,
+    pub is_online: bool,
+    pub created_at: u64,
+    pub last_active_at: u64,
+    pub metadata: ClientMetadata,
+}
+
+impl AdminPlayerDto {
+    fn new(username: &Username, player: &Player) -> Self {
+        Self {
+            username: username.clone(),
+            is_online: player.ws.is_some(),
+            created_at: player.created_at,
+            last_active_at: player.last_active_at(),
+            metadata: player.metadata.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminRoomDto {
+    pub code: RoomCode,
+    pub leader: Username,
+    pub public: bool,
+    pub cleaning_up: bool,
+    pub players: Vec<AdminPlayerDto>,
+}
+
+async fn get_room(state: &State, room_code: &RoomCode) -> MuuzikaResult<WrappedRoom> {
+    state
+        .rooms
+        .read()
+        .await
+        .get(room_code)
+        .cloned()
+        .ok_or_else(|| MuuzikaError::RoomNotFound {
+            room_code: room_code.clone(),
+        })
+}
+
+// Everything the server holds about a room, for troubleshooting a specific game
+pub async fn dump_room(state: &State, room_code: &RoomCode) -> MuuzikaResult<AdminRoomDto> {
+    let wrapped_room = get_room(state, room_code).await?;
+    let room = wrapped_room.read().await;
+
+    let mut players: Vec<AdminPlayerDto> = room
+        .players
+        .iter()
+        .map(|(username, player)| AdminPlayerDto::new(username, player))
+        .collect();
+    players.sort_by_key(|player| player.created_at);
+
+    Ok(AdminRoomDto {
+        code: room.code.clone(),
+        leader: room.leader.clone(),
+        public: room.public,
+        cleaning_up: room.cleaning_up,
+        players,
+    })
+}
+
+pub async fn dump_player(
+    state: &State,
+    room_code: &RoomCode,
+    username: &Username,
+) -> MuuzikaResult<AdminPlayerDto> {
+    let wrapped_room = get_room(state, room_code).await?;
+    let room = wrapped_room.read().await;
+    Ok(AdminPlayerDto::new(username, room.get_player(username)?))
+}
@@ -0,0 +1,159 @@
+use std::io::Write;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use serde::Serialize;
+
+use crate::helpers::spawn_supervised;
+use crate::rooms::RoomCode;
+use crate::serialization::serialize_utc_date_time;
+
+const LOG_TARGET: &str = "muuzika::analytics";
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", content = "data")]
+pub enum AnalyticsEvent {
+    #[serde(rename_all = "camelCase")]
+    RoomCreated { room_code: RoomCode },
+    #[serde(rename_all = "camelCase")]
+    PlayerJoined {
+        room_code: RoomCode,
+        player_count: usize,
+    },
+    #[serde(rename_all = "camelCase")]
+    PlayerLeft {
+        room_code: RoomCode,
+        player_count: usize,
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomClosed { room_code: RoomCode },
+}
+
+/// Receives lifecycle events for product analytics.
+///
+/// `emit` is called while room locks may be held, so implementations must not block,
+/// anything slow has to be spawned off.
+pub trait EventSink: Send + Sync {
+    fn emit(&self, event: AnalyticsEvent);
+}
+
+pub struct NoopSink;
+
+impl EventSink for NoopSink {
+    fn emit(&self, _event: AnalyticsEvent) {}
+}
+
+/// Posts every event as JSON to a webhook, fire-and-forget.
+///
+/// Only plain `http://` URLs are supported.
+pub struct WebhookSink {
+    url: Uri,
+    client: Client<HttpConnector>,
+}
+
+impl WebhookSink {
+    pub fn new(url: Uri) -> Self {
+        Self {
+            url,
+            client: Client::new(),
+        }
+    }
+}
+
+impl EventSink for WebhookSink {
+    fn emit(&self, event: AnalyticsEvent) {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                log::warn!(target: LOG_TARGET, "Could not serialize event {:?}: {:?}", event, e);
+                return;
+            }
+        };
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header("content-type", "application/json")
+            .body(Body::from(body));
+
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                log::warn!(target: LOG_TARGET, "Could not build webhook request: {:?}", e);
+                return;
+            }
+        };
+
+        let client = self.client.clone();
+        spawn_supervised("analytics webhook".to_string(), async move {
+            match client.request(request).await {
+                Ok(response) if !response.status().is_success() => {
+                    log::debug!(target: LOG_TARGET, "Webhook answered with status {}", response.status());
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::debug!(target: LOG_TARGET, "Webhook request failed: {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Sends every event to all of the wrapped sinks.
+pub struct FanoutSink(pub Vec<Arc<dyn EventSink>>);
+
+impl EventSink for FanoutSink {
+    fn emit(&self, event: AnalyticsEvent) {
+        for sink in &self.0 {
+            sink.emit(event.clone());
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EventLogLine {
+    #[serde(serialize_with = "serialize_utc_date_time")]
+    timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(flatten)]
+    event: AnalyticsEvent,
+}
+
+/// Writes every event as a line of JSON with its timestamp, for tracing a room afterwards
+/// with `grep`/`jq` on the room code.
+///
+/// The writes happen on a thread of their own, so a slow disk never blocks `emit`.
+pub struct JsonLinesSink {
+    tx: mpsc::Sender<EventLogLine>,
+}
+
+impl JsonLinesSink {
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> Self {
+        let (tx, rx) = mpsc::channel::<EventLogLine>();
+
+        thread::spawn(move || {
+            for line in rx {
+                let result = serde_json::to_writer(&mut writer, &line)
+                    .map_err(std::io::Error::from)
+                    .and_then(|_| writer.write_all(b"\n"))
+                    .and_then(|_| writer.flush());
+                if let Err(e) = result {
+                    log::warn!(target: LOG_TARGET, "Could not write event {:?}: {:?}", line.event, e);
+                }
+            }
+        });
+
+        Self { tx }
+    }
+}
+
+impl EventSink for JsonLinesSink {
+    fn emit(&self, event: AnalyticsEvent) {
+        let line = EventLogLine {
+            timestamp: chrono::Utc::now(),
+            event,
+        };
+        let _ = self.tx.send(line);
+    }
+}
@@ -1,26 +1,42 @@
 use std::collections::HashSet;
 
+use jsonwebtoken::errors::ErrorKind;
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 
-use crate::errors::MuuzikaResult;
+use crate::errors::{MuuzikaError, MuuzikaResult};
 use crate::rooms::{RoomCode, Username};
 
+#[derive(Clone)]
+pub struct JwtConfig {
+    pub secret: String,
+    // Issuer and audience are only set and checked when configured, they keep tokens
+    // from other services sharing the same secret from being accepted here
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JwtClaims {
     pub iat: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
     pub room_code: RoomCode,
     pub username: Username,
 }
 
 pub fn encode_token(
-    secret: &String,
+    config: &JwtConfig,
     iat: u64,
     room_code: &RoomCode,
     username: &Username,
 ) -> MuuzikaResult<String> {
     let claims = JwtClaims {
         iat,
+        iss: config.issuer.clone(),
+        aud: config.audience.clone(),
         room_code: room_code.clone(),
         username: username.clone(),
     };
@@ -28,22 +44,38 @@ pub fn encode_token(
     let token = encode(
         &Header::default(),
         &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
+        &EncodingKey::from_secret(config.secret.as_bytes()),
     )?;
 
     Ok(token)
 }
 
-pub fn decode_token(secret: &String, token: &String) -> MuuzikaResult<JwtClaims> {
+pub fn decode_token(config: &JwtConfig, token: &str) -> MuuzikaResult<JwtClaims> {
     let mut validation = Validation::default();
     validation.validate_exp = false;
-    validation.required_spec_claims = HashSet::with_capacity(0);
+    validation.required_spec_claims = HashSet::with_capacity(2);
+
+    if let Some(issuer) = &config.issuer {
+        validation.set_issuer(&[issuer]);
+        validation.required_spec_claims.insert("iss".to_string());
+    }
+
+    if let Some(audience) = &config.audience {
+        validation.set_audience(&[audience]);
+        validation.required_spec_claims.insert("aud".to_string());
+    }
 
     let claims = decode::<JwtClaims>(
         token,
-        &DecodingKey::from_secret(secret.as_bytes()),
+        &DecodingKey::from_secret(config.secret.as_bytes()),
         &validation,
-    )?;
+    )
+    .map_err(|e| match e.kind() {
+        ErrorKind::InvalidIssuer
+        | ErrorKind::InvalidAudience
+        | ErrorKind::MissingRequiredClaim(_) => MuuzikaError::TokenInvalid,
+        _ => e.into(),
+    })?;
 
     Ok(claims.claims)
 }
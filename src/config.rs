@@ -0,0 +1,110 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use hyper::Uri;
+
+use crate::auth::JwtConfig;
+use crate::helpers::{get_env_optional, get_env_or_default, get_env_or_panic};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoomCodeMode {
+    #[default]
+    Random,
+    // Ascending codes, so staging test scripts know which code comes next
+    Sequential,
+}
+
+impl FromStr for RoomCodeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "random" => Ok(RoomCodeMode::Random),
+            "sequential" => Ok(RoomCodeMode::Sequential),
+            _ => Err(format!("Unknown room code mode: {}", s)),
+        }
+    }
+}
+
+// What happens once a disconnected leader's grace period runs out and other players
+// are still around
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LeaderDisconnectPolicy {
+    #[default]
+    Reelect,
+    CloseRoom,
+}
+
+impl FromStr for LeaderDisconnectPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "reelect" => Ok(LeaderDisconnectPolicy::Reelect),
+            "close" => Ok(LeaderDisconnectPolicy::CloseRoom),
+            _ => Err(format!("Unknown leader disconnect policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub jwt: JwtConfig,
+    pub room_code_length: u8,
+    pub room_code_mode: RoomCodeMode,
+    pub analytics_webhook_url: Option<Uri>,
+    pub require_ws_nonce: bool,
+    pub player_cleanup_delay: Duration,
+    pub room_cleanup_delay: Duration,
+    pub disconnect_broadcast_delay: Duration,
+    pub idempotency_key_ttl: Duration,
+    pub outbox_capacity: usize,
+    // Admin endpoints are disabled unless set
+    pub admin_token: Option<String>,
+    pub timer_shards: usize,
+    pub username_display_length: usize,
+    // Rooms a single IP can have open at once, unlimited if unset
+    pub max_rooms_per_host: Option<usize>,
+    pub leader_disconnect_policy: LeaderDisconnectPolicy,
+    // File the JSON-lines event log is appended to, "-" for stdout, no log if unset
+    pub event_log_path: Option<String>,
+    // How long results of messages sent with an `id` are kept to answer retries
+    pub message_dedupe_window: Duration,
+    pub broadcast_player_count: bool,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            jwt: JwtConfig {
+                secret: get_env_or_panic("JWT_SECRET"),
+                issuer: get_env_optional("JWT_ISSUER"),
+                audience: get_env_optional("JWT_AUDIENCE"),
+            },
+            room_code_length: get_env_or_default("ROOM_CODE_LENGTH", 4),
+            room_code_mode: get_env_or_default("ROOM_CODE_MODE", RoomCodeMode::default()),
+            analytics_webhook_url: get_env_optional("ANALYTICS_WEBHOOK_URL"),
+            require_ws_nonce: get_env_or_default("REQUIRE_WS_NONCE", false),
+            player_cleanup_delay: get_env_secs("PLAYER_CLEANUP_SECS", 10),
+            room_cleanup_delay: get_env_secs("ROOM_CLEANUP_SECS", 10),
+            disconnect_broadcast_delay: get_env_secs("DISCONNECT_BROADCAST_SECS", 2),
+            idempotency_key_ttl: get_env_secs("IDEMPOTENCY_KEY_TTL_SECS", 60),
+            outbox_capacity: get_env_or_default("OUTBOX_CAPACITY", 0),
+            admin_token: get_env_optional("ADMIN_TOKEN"),
+            timer_shards: get_env_or_default("TIMER_SHARDS", 1),
+            username_display_length: get_env_or_default("USERNAME_DISPLAY_LENGTH", 16),
+            max_rooms_per_host: get_env_optional("MAX_ROOMS_PER_HOST"),
+            leader_disconnect_policy: get_env_or_default(
+                "LEADER_DISCONNECT_POLICY",
+                LeaderDisconnectPolicy::default(),
+            ),
+            event_log_path: get_env_optional("EVENT_LOG_PATH"),
+            message_dedupe_window: get_env_secs("MESSAGE_DEDUPE_SECS", 10),
+            broadcast_player_count: get_env_or_default("BROADCAST_PLAYER_COUNT", false),
+        }
+    }
+}
+
+fn get_env_secs(key: &str, default: u64) -> Duration {
+    Duration::from_secs(get_env_or_default(key, default))
+}
@@ -31,6 +31,24 @@ pub enum MuuzikaError {
     #[serde(rename_all = "camelCase")]
     RoomNotFound { room_code: RoomCode },
 
+    #[error("Room {room_code} is closing, try again")]
+    #[serde(rename_all = "camelCase")]
+    RoomClosing { room_code: RoomCode },
+
+    #[error("Room {room_code} is a practice room, it can't be joined")]
+    #[serde(rename_all = "camelCase")]
+    PracticeRoom { room_code: RoomCode },
+
+    #[error("Room code {room_code} is already in use")]
+    #[serde(rename_all = "camelCase")]
+    RoomCodeInUse { room_code: RoomCode },
+
+    #[error("Too many open rooms, at most {limit} are allowed at once")]
+    TooManyRooms { limit: usize },
+
+    #[error("Idempotency key \"{key}\" was already used for a different request")]
+    IdempotencyKeyReused { key: String },
+
     #[error("Out of room codes")]
     OutOfRoomCodes,
 
@@ -39,6 +57,8 @@ pub enum MuuzikaError {
     UsernameTaken {
         room_code: RoomCode,
         username: Username,
+        // Free in the room as of the error, not reserved
+        suggestions: Vec<Username>,
     },
 
     #[error("Player \"{username}\" is not in room {room_code}")]
@@ -48,33 +68,82 @@ pub enum MuuzikaError {
         username: Username,
     },
 
-    #[error("Expired token")]
-    ExpiredToken,
+    #[error("Avatar \"{avatar}\" is not one of the allowed avatars")]
+    InvalidAvatar { avatar: String },
+
+    #[error("Username \"{username}\" {reason}")]
+    InvalidUsername {
+        username: String,
+        reason: &'static str,
+    },
 
-    #[error("Token not sent")]
-    TokenNotSent,
+    #[error("Request validation failed")]
+    ValidationFailed { fields: Vec<FieldError> },
+
+    #[error("Token was not issued for this server")]
+    TokenInvalid,
+
+    #[error("Connection nonce is missing or was already used")]
+    InvalidNonce,
+
+    #[error("Admin token is missing or invalid")]
+    AdminUnauthorized,
 
     #[error("Connection was established in another device")]
     ConnectedInAnotherDevice,
+
+    #[error("The leader left room {room_code}, so it was closed")]
+    #[serde(rename_all = "camelCase")]
+    LeaderLeft { room_code: RoomCode },
+
+    #[error("Expected a JSON body, send it with \"Content-Type: application/json\" (got {content_type:?})")]
+    #[serde(rename_all = "camelCase")]
+    UnsupportedContentType { content_type: String },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub reason: &'static str,
 }
 
 impl MuuzikaError {
     pub fn code(&self) -> StatusCode {
         match self {
             MuuzikaError::RoomNotFound { .. } => StatusCode::NOT_FOUND,
-            MuuzikaError::OutOfRoomCodes => StatusCode::SERVICE_UNAVAILABLE,
-            MuuzikaError::UsernameTaken { .. } | MuuzikaError::ConnectedInAnotherDevice { .. } => {
+            MuuzikaError::OutOfRoomCodes | MuuzikaError::RoomClosing { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            MuuzikaError::UsernameTaken { .. } | MuuzikaError::ConnectedInAnotherDevice => {
                 StatusCode::CONFLICT
             }
-            MuuzikaError::PlayerNotInRoom { .. } => StatusCode::FORBIDDEN,
-            MuuzikaError::JwtError(_) | MuuzikaError::ExpiredToken | MuuzikaError::TokenNotSent => {
-                StatusCode::UNAUTHORIZED
+            MuuzikaError::TooManyRooms { .. } => StatusCode::TOO_MANY_REQUESTS,
+            MuuzikaError::IdempotencyKeyReused { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            MuuzikaError::LeaderLeft { .. } => StatusCode::GONE,
+            MuuzikaError::PlayerNotInRoom { .. } | MuuzikaError::PracticeRoom { .. } => {
+                StatusCode::FORBIDDEN
             }
+            MuuzikaError::InvalidUsername { .. }
+            | MuuzikaError::InvalidAvatar { .. }
+            | MuuzikaError::ValidationFailed { .. } => StatusCode::BAD_REQUEST,
+            MuuzikaError::UnsupportedContentType { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            MuuzikaError::JwtError(_)
+            | MuuzikaError::TokenInvalid
+            | MuuzikaError::InvalidNonce
+            | MuuzikaError::AdminUnauthorized => StatusCode::UNAUTHORIZED,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
+impl MuuzikaError {
+    // Close codes in the 4000-4999 range are free for applications to use,
+    // we mirror the HTTP status so clients can handle both the same way
+    pub fn close_code(&self) -> u16 {
+        4000 + self.code().as_u16()
+    }
+}
+
 impl Reject for MuuzikaError {}
 
 pub type MuuzikaResult<T> = Result<T, MuuzikaError>;
@@ -89,6 +158,9 @@ pub struct ErrorResponse {
     pub error: String,
     pub message: String,
     pub data: Option<serde_json::Value>,
+    // Set when the connection is closed right after the error, so clients can tell
+    // a full disconnect apart from a hiccup worth a toast
+    pub fatal: bool,
 }
 
 impl ErrorResponse {
@@ -104,9 +176,15 @@ impl ErrorResponse {
             error,
             message,
             data,
+            fatal: false,
         }
     }
 
+    pub fn fatal(mut self) -> Self {
+        self.fatal = true;
+        self
+    }
+
     pub fn no_data(code: StatusCode, error: String, message: String) -> Self {
         ErrorResponse::new(code, error, message, None)
     }
@@ -117,15 +195,14 @@ impl From<&MuuzikaError> for ErrorResponse {
         let error: String;
         let data: Option<serde_json::Value>;
 
-        if let Some(json_value) = serde_json::to_value(muuzika_error).ok() {
+        if let Ok(json_value) = serde_json::to_value(muuzika_error) {
             error = json_value
                 .get("error")
-                .map(|v| v.as_str())
-                .flatten()
-                .unwrap_or_else(|| "Unknown")
+                .and_then(|v| v.as_str())
+                .unwrap_or("Unknown")
                 .to_string();
 
-            data = json_value.get("data").map(|v| v.clone());
+            data = json_value.get("data").cloned();
         } else {
             error = "Unknown".to_string();
             data = None;
@@ -151,12 +228,6 @@ pub fn get_response_from_rejection(err: Rejection) -> ErrorResponse {
             "NotFound".to_string(),
             "Not found".to_string(),
         )
-    } else if let Some(_) = err.find::<warp::reject::MethodNotAllowed>() {
-        ErrorResponse::no_data(
-            StatusCode::METHOD_NOT_ALLOWED,
-            "MethodNotAllowed".to_string(),
-            "Method not allowed".to_string(),
-        )
     } else if let Some(invalid_header) = err.find::<warp::reject::InvalidHeader>() {
         ErrorResponse::no_data(
             StatusCode::BAD_REQUEST,
@@ -219,11 +290,15 @@ pub fn get_response_from_rejection(err: Rejection) -> ErrorResponse {
             "CorsForbidden".to_string(),
             cors_forbidden.to_string(),
         )
-    } else {
+    // Last, since a method mismatch on a sibling route (e.g. GET /rooms) is carried
+    // along with the rejection of the route that actually matched
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         ErrorResponse::no_data(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Unknown".to_string(),
-            "Unknown error".to_string(),
+            StatusCode::METHOD_NOT_ALLOWED,
+            "MethodNotAllowed".to_string(),
+            "Method not allowed".to_string(),
         )
+    } else {
+        ErrorResponse::from(MuuzikaError::Unknown)
     }
 }
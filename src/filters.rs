@@ -1,12 +1,16 @@
 use std::convert::Infallible;
+use std::net::SocketAddr;
 
+use percent_encoding::percent_decode_str;
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 use warp::http::StatusCode;
 use warp::{Filter, Rejection, Reply};
 
-use crate::errors::get_response_from_rejection;
+use crate::admin;
+use crate::errors::{get_response_from_rejection, MuuzikaError};
 use crate::lobby;
-use crate::rooms::RoomCode;
+use crate::rooms::{ClientMetadata, RoomCode, Username};
 use crate::state::State;
 use crate::ws::{handle_ws, WsQuery};
 
@@ -15,6 +19,7 @@ fn ws(state: State) -> impl Filter<Extract = impl Reply, Error = Rejection> + Cl
         .and(warp::ws())
         .and(with_state(state))
         .and(warp::query::<WsQuery>())
+        .and(client_metadata())
         .and_then(handle_ws)
 }
 
@@ -22,52 +27,224 @@ fn create_room(state: State) -> impl Filter<Extract = impl Reply, Error = Reject
     warp::path!("rooms")
         .and(warp::post())
         .and(with_state(state))
-        .and(json_body::<lobby::CreateOrJoinRoomRequest>())
-        .and_then(|state, request| async move {
-            lobby::create_room(&state, &request)
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(create_or_join_room_body())
+        .and(client_metadata())
+        .and_then(|state, idempotency_key, request, metadata| async move {
+            lobby::create_room_idempotent(&state, idempotency_key, &request, &metadata)
                 .await
                 .map_err(warp::reject::custom)
         })
         .map(|response| warp::reply::with_status(warp::reply::json(&response), StatusCode::CREATED))
 }
 
+fn list_rooms(state: State) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("rooms")
+        .and(warp::get())
+        .and(with_state(state))
+        .and(warp::query::<lobby::RoomListQuery>())
+        .then(|state, query| async move { lobby::list_public_rooms(&state, &query).await })
+        .map(|response| warp::reply::json(&response))
+}
+
 fn join_room(state: State) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("rooms" / RoomCode)
         .and(warp::post())
         .and(with_state(state))
-        .and(json_body::<lobby::CreateOrJoinRoomRequest>())
-        .and_then(|room_code, state, request| async move {
-            lobby::join_room(&state, &room_code, &request)
+        .and(create_or_join_room_body())
+        .and(client_metadata())
+        .and_then(|room_code, state, request, metadata| async move {
+            lobby::join_room(&state, &room_code, &request, &metadata)
                 .await
                 .map_err(warp::reject::custom)
         })
         .map(|response| warp::reply::with_status(warp::reply::json(&response), StatusCode::CREATED))
 }
 
+fn admin_gc(state: State) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("admin" / "gc")
+        .and(warp::post())
+        .and(admin_auth(state.clone()))
+        .and(with_state(state))
+        .then(|state| async move { admin::collect_garbage(&state).await })
+        .map(|report| warp::reply::json(&report))
+}
+
+fn admin_broadcast(state: State) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("admin" / "broadcast")
+        .and(warp::post())
+        .and(admin_auth(state.clone()))
+        .and(with_state(state))
+        .and(json_body::<admin::AnnouncementRequest>())
+        .and_then(|state, request| async move {
+            admin::announce(&state, &request)
+                .await
+                .map_err(warp::reject::custom)
+        })
+        .map(|response| warp::reply::json(&response))
+}
+
+fn admin_room(state: State) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("admin" / "rooms" / RoomCode)
+        .and(warp::get())
+        .and(admin_auth(state.clone()))
+        .and(with_state(state))
+        .and_then(|room_code, state| async move {
+            admin::dump_room(&state, &room_code)
+                .await
+                .map_err(warp::reject::custom)
+        })
+        .map(|response| warp::reply::json(&response))
+}
+
+fn admin_player(state: State) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("admin" / "rooms" / RoomCode / "players" / ..)
+        .and(username_param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(admin_auth(state.clone()))
+        .and(with_state(state))
+        .and_then(|room_code, username, state| async move {
+            admin::dump_player(&state, &room_code, &username)
+                .await
+                .map_err(warp::reject::custom)
+        })
+        .map(|response| warp::reply::json(&response))
+}
+
 pub fn filters(state: State) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     ws(state.clone())
         .or(create_room(state.clone()))
+        .or(list_rooms(state.clone()))
         .or(join_room(state.clone()))
+        .or(admin_gc(state.clone()))
+        .or(admin_broadcast(state.clone()))
+        .or(admin_room(state.clone()))
+        .or(admin_player(state.clone()))
 }
 
 fn with_state(state: State) -> impl Filter<Extract = (State,), Error = Infallible> + Clone {
     warp::any().map(move || state.clone())
 }
 
+// Admin routes don't exist at all unless an admin token is configured
+fn admin_auth(state: State) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let admin_token = state.config.admin_token.clone();
+            async move {
+                let admin_token = admin_token.ok_or_else(warp::reject::not_found)?;
+                let sent = authorization
+                    .as_deref()
+                    .and_then(|a| a.strip_prefix("Bearer "));
+
+                if sent == Some(admin_token.as_str()) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(MuuzikaError::AdminUnauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn client_metadata() -> impl Filter<Extract = (ClientMetadata,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("user-agent")
+        .and(warp::header::optional::<String>("accept-language"))
+        .and(warp::addr::remote())
+        .map(
+            |user_agent, accept_language, addr: Option<SocketAddr>| ClientMetadata {
+                user_agent,
+                accept_language,
+                ip: addr.map(|addr| addr.ip()),
+            },
+        )
+}
+
+// `warp::path::param` turns a failed parse into a 404 as if the route didn't exist,
+// an invalid name should be reported as a bad request instead. Segments arrive still
+// percent-encoded, so names with spaces or accents have to be decoded first
+pub fn username_param() -> impl Filter<Extract = (Username,), Error = Rejection> + Clone {
+    warp::path::param::<String>().and_then(|username: String| async move {
+        let username = percent_decode_str(&username)
+            .decode_utf8_lossy()
+            .into_owned();
+        Username::try_new(username).map_err(warp::reject::custom)
+    })
+}
+
+fn create_or_join_room_body(
+) -> impl Filter<Extract = (lobby::CreateOrJoinRoomRequest,), Error = Rejection> + Clone {
+    json_body::<lobby::CreateOrJoinRoomBody>().and_then(
+        |body: lobby::CreateOrJoinRoomBody| async move {
+            body.validate().map_err(warp::reject::custom)
+        },
+    )
+}
+
 fn json_body<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
 where
     T: DeserializeOwned + Send,
 {
     // When accepting a body, we want a JSON body
     // (and to reject huge payloads)...
-    warp::body::content_length_limit(1024 * 16).and(warp::body::json::<T>())
+    json_content_type()
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json::<T>())
+}
+
+// warp's own rejection for a wrong content type doesn't say what we expect,
+// which isn't obvious to clients sending form-encoded bodies. Like warp, a body
+// without a content type is assumed to be JSON, older clients don't send one
+fn json_content_type() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and_then(|content_type: Option<String>| async move {
+            let content_type = match content_type {
+                Some(content_type) => content_type,
+                None => return Ok(()),
+            };
+            let is_json = content_type
+                .split(';')
+                .next()
+                .is_some_and(|c| c.trim().eq_ignore_ascii_case("application/json"));
+
+            if is_json {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(MuuzikaError::UnsupportedContentType {
+                    content_type,
+                }))
+            }
+        })
+        .untuple_one()
 }
 
 pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     let response = get_response_from_rejection(err);
 
-    Ok(warp::reply::with_status(
-        warp::reply::json(&response),
-        response.code,
-    ))
+    Ok(error_reply(&response, response.code))
+}
+
+// `warp::reply::json` answers with an empty 500 if serializing fails, the error path
+// has to produce a well-formed body no matter what
+pub fn error_reply<T>(response: &T, code: StatusCode) -> warp::reply::Response
+where
+    T: Serialize,
+{
+    const FALLBACK: &str =
+        r#"{"code":500,"error":"Unknown","message":"Unknown error","data":null,"fatal":false}"#;
+
+    let (body, code) = match serde_json::to_string(response) {
+        Ok(body) => (body, code),
+        Err(e) => {
+            log::error!(target: "muuzika::filters::error_reply", "Could not serialize error response: {:?}", e);
+            (FALLBACK.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+
+    warp::http::Response::builder()
+        .status(code)
+        .header("content-type", "application/json")
+        .body(body.into())
+        .unwrap_or_else(|_| warp::reply::Response::new(FALLBACK.into()))
 }
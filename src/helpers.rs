@@ -1,6 +1,11 @@
+use std::any::Any;
 use std::env;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
 use std::str::FromStr;
 
+use futures_util::FutureExt;
+
 pub fn get_env_or_default<T>(key: &str, default: T) -> T
 where
     T: FromStr,
@@ -27,6 +32,51 @@ where
     }
 }
 
+pub fn get_env_optional<T>(key: &str) -> Option<T>
+where
+    T: FromStr,
+{
+    match env::var(key) {
+        Ok(value) => match value.parse::<T>() {
+            Ok(value) => Some(value),
+            Err(_) => panic!("Could not parse environment variable: {}", key),
+        },
+        Err(_) => None,
+    }
+}
+
+pub fn spawn_supervised<F>(context: String, future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    spawn_supervised_with_fallback(context, future, async {});
+}
+
+// Panics in spawned tasks are otherwise only visible through the JoinHandle, which
+// nobody awaits, so they'd go unnoticed while the state they were handling wedges
+pub fn spawn_supervised_with_fallback<F, R>(context: String, future: F, on_panic: R)
+where
+    F: Future<Output = ()> + Send + 'static,
+    R: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(panic) = AssertUnwindSafe(future).catch_unwind().await {
+            log::error!(target: "muuzika::tasks", "Task \"{}\" panicked: {}", context, panic_message(&panic));
+            on_panic.await;
+        }
+    });
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
 #[macro_export]
 macro_rules! log_identifier {
     () => {
@@ -1,114 +1,332 @@
+use std::collections::hash_map::Entry;
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::Duration;
 
-use futures_util::TryFutureExt;
+use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{oneshot, RwLock};
-use tokio::time::timeout;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{oneshot, OnceCell, RwLock};
 
+use crate::analytics::AnalyticsEvent;
 use crate::auth::{decode_token, encode_token};
-use crate::errors::{MuuzikaError, MuuzikaResult};
+use crate::config::LeaderDisconnectPolicy;
+use crate::errors::{FieldError, MuuzikaError, MuuzikaResult};
+use crate::helpers::{spawn_supervised, spawn_supervised_with_fallback};
 use crate::messages::ServerMessage;
-use crate::rooms::{Player, Room, RoomCode, RoomSyncDto, Username};
+use crate::rooms::{
+    ClientMetadata, DeadConnection, DisconnectPolicy, Player, PublicRoomDto, Room, RoomCode,
+    RoomSyncDto, Username,
+};
 use crate::state::{State, WrappedRoom};
 use crate::ws::WsConnection;
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CreateOrJoinRoomRequest {
     pub username: Username,
+    // Only used when creating a room, public rooms are listed in `GET /rooms`
+    pub public: bool,
+    pub disconnect_policy: DisconnectPolicy,
+    // Single-player rooms for warming up, see `RoomCode::practice`
+    pub practice: bool,
 }
 
-#[derive(Serialize)]
+// What's actually sent, fields are only checked in `validate` so every problem
+// can be reported at once instead of failing on the first one serde hits
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateOrJoinRoomBody {
+    pub username: String,
+    #[serde(default)]
+    pub public: bool,
+    #[serde(default)]
+    pub disconnect_policy: DisconnectPolicy,
+    #[serde(default)]
+    pub practice: bool,
+}
+
+impl CreateOrJoinRoomBody {
+    pub fn validate(self) -> MuuzikaResult<CreateOrJoinRoomRequest> {
+        let mut fields = Vec::new();
+
+        let username = match Username::try_new(self.username) {
+            Ok(username) => Some(username),
+            Err(MuuzikaError::InvalidUsername { reason, .. }) => {
+                fields.push(FieldError {
+                    field: "username",
+                    reason,
+                });
+                None
+            }
+            Err(e) => return Err(e),
+        };
+
+        match username {
+            Some(username) if fields.is_empty() => Ok(CreateOrJoinRoomRequest {
+                username,
+                public: self.public,
+                disconnect_policy: self.disconnect_policy,
+                practice: self.practice,
+            }),
+            _ => Err(MuuzikaError::ValidationFailed { fields }),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct RoomJoinedResponse {
     pub room_code: RoomCode,
     pub token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+// A create made under an idempotency key, along with the request it was made for.
+// Dropping the entry also drops `_cancel_eviction`, cancelling its eviction timer
+pub struct IdempotentCreate {
+    request: CreateOrJoinRoomRequest,
+    response: OnceCell<RoomJoinedResponse>,
+    _cancel_eviction: oneshot::Sender<()>,
+}
+
+// Mobile clients retry `POST /rooms` after timeouts, the same key within the TTL
+// gets the room that was already created instead of a new one. The key only stands
+// for the exact request it was first sent with, and only while that room is still
+// the one the response points to
+pub async fn create_room_idempotent(
+    state: &State,
+    idempotency_key: Option<String>,
+    request: &CreateOrJoinRoomRequest,
+    metadata: &ClientMetadata,
+) -> MuuzikaResult<RoomJoinedResponse> {
+    const LOG_TARGET: &str = "muuzika::lobby::create_room_idempotent";
+
+    let key = match idempotency_key {
+        Some(key) => key,
+        None => return create_room(state, request, metadata).await,
+    };
+
+    let (entry, response) = create_room_once(state, &key, request, metadata).await?;
+    if response_is_live(state, &response).await {
+        return Ok(response);
+    }
+
+    // The room was closed since, and its code may already belong to another room
+    log::debug!(target: LOG_TARGET, "Room {} of idempotency key \"{}\" is gone, creating a new one", response.room_code, key);
+    remove_idempotency_key(state, &key, &entry).await;
+    create_room_once(state, &key, request, metadata)
+        .await
+        .map(|(_, response)| response)
+}
+
+async fn create_room_once(
+    state: &State,
+    key: &str,
+    request: &CreateOrJoinRoomRequest,
+    metadata: &ClientMetadata,
+) -> MuuzikaResult<(Arc<IdempotentCreate>, RoomJoinedResponse)> {
+    const LOG_TARGET: &str = "muuzika::lobby::create_room_once";
+
+    let entry = {
+        let mut keys = state.idempotency_keys.write().await;
+        if let Some(entry) = keys.get(key) {
+            log::debug!(target: LOG_TARGET, "Idempotency key \"{}\" was already used", key);
+            entry.clone()
+        } else {
+            let (tx, rx) = oneshot::channel::<()>();
+            let entry = Arc::new(IdempotentCreate {
+                request: request.clone(),
+                response: OnceCell::new(),
+                _cancel_eviction: tx,
+            });
+            keys.insert(key.to_string(), entry.clone());
+            schedule_idempotency_key_eviction(state.clone(), key.to_string(), &entry, rx);
+            entry
+        }
+    };
+
+    if entry.request != *request {
+        log::debug!(target: LOG_TARGET, "Idempotency key \"{}\" was used for a different request", key);
+        return Err(MuuzikaError::IdempotencyKeyReused {
+            key: key.to_string(),
+        });
+    }
+
+    let response = entry
+        .response
+        .get_or_try_init(|| create_room(state, request, metadata))
+        .await?
+        .clone();
+    Ok((entry, response))
+}
+
+// Whether the leader the response was issued to is still in the room it points to
+async fn response_is_live(state: &State, response: &RoomJoinedResponse) -> bool {
+    let claims = match decode_token(&state.config.jwt, &response.token) {
+        Ok(claims) => claims,
+        Err(_) => return false,
+    };
+    let wrapped_room = match state.rooms.read().await.get(&response.room_code) {
+        Some(wrapped_room) => wrapped_room.clone(),
+        None => return false,
+    };
+
+    let room = wrapped_room.read().await;
+    !room.cleaning_up
+        && room
+            .get_player(&claims.username)
+            .is_ok_and(|player| player.created_at == claims.iat)
+}
+
+// Only if it's still the same entry, a newer create may have taken the key over
+async fn remove_idempotency_key(state: &State, key: &str, entry: &Arc<IdempotentCreate>) {
+    let mut keys = state.idempotency_keys.write().await;
+    if keys
+        .get(key)
+        .is_some_and(|current| Arc::ptr_eq(current, entry))
+    {
+        keys.remove(key);
+    }
 }
 
 pub async fn create_room(
     state: &State,
     request: &CreateOrJoinRoomRequest,
+    metadata: &ClientMetadata,
 ) -> MuuzikaResult<RoomJoinedResponse> {
-    const LOG_TARGET: &'static str = "muuzika::lobby::create_room";
+    const LOG_TARGET: &str = "muuzika::lobby::create_room";
     let identifier = log_identifier!();
 
     log::debug!(target: LOG_TARGET, "{} | Creating room, {:?}", identifier, request);
 
-    let (room_code, remaining_codes) = pop_room_code(state).await.map_err(|e| {
-        log::debug!(target: LOG_TARGET, "{} | Error obtaining room code: {:?}", identifier, e);
-        e
-    })?;
+    // Practice rooms don't take up a code, so they don't count
+    let host = metadata.ip.filter(|_| !request.practice);
+    if let Some(host) = host {
+        reserve_host_slot(state, host).await.inspect_err(|_| {
+            log::debug!(target: LOG_TARGET, "{} | Host {} has too many rooms", identifier, host);
+        })?;
+    }
+
+    let room_code = if request.practice {
+        Ok((RoomCode::practice(), state.available_codes.remaining()))
+    } else {
+        pop_room_code(state)
+    };
+    let (room_code, remaining_codes) = match room_code {
+        Ok(room_code) => room_code,
+        Err(e) => {
+            log::debug!(target: LOG_TARGET, "{} | Error obtaining room code: {:?}", identifier, e);
+            release_host_slot(state, host).await;
+            return Err(e);
+        }
+    };
 
     log::debug!(target: LOG_TARGET, "{} | Got room code {}, {} remaining", identifier, room_code, remaining_codes);
 
-    match create_room_with_code(state, &request.username, &room_code).await {
+    let result = create_room_with_code(state, request, &room_code, metadata).await;
+    if result.is_err() {
+        release_host_slot(state, host).await;
+    }
+
+    match result {
         Ok(response) => {
             log::debug!(target: LOG_TARGET, "{} | Created room {} with leader \"{}\" successfully", identifier, room_code, request.username);
+            state
+                .analytics
+                .emit(AnalyticsEvent::RoomCreated { room_code });
             Ok(response)
         }
+        Err(e @ MuuzikaError::RoomCodeInUse { .. }) => {
+            // The code belongs to the room already using it, it goes back once that one is closed
+            log::error!(target: LOG_TARGET, "{} | Room code {} was handed out while in use", identifier, room_code);
+            Err(e)
+        }
         Err(e) => {
             log::debug!(target: LOG_TARGET, "{} | Error creating room: {:?}, will return room code {}", identifier, e, room_code);
-            let remaining_codes = push_room_code(state, room_code).await;
+            let remaining_codes = push_room_code(state, room_code);
             log::debug!(target: LOG_TARGET, "{} | Returned room code, {} remaining", identifier, remaining_codes);
             Err(e)
         }
     }
 }
 
+// Room codes are short, so anyone can probe `POST /rooms/{code}` looking for active rooms.
+// Unknown codes do the same token work as a real join and fail with the same error shape,
+// so active codes can't be trivially found by response time. Not strictly timing-safe.
+// The status still differs (404 against 409 for a taken name), since a client has to tell
+// "wrong code" from "pick another name" apart, so a code with a known player name in it
+// can still be confirmed. The suggestions sent along with a 409 are random, so they
+// reveal nothing about the room beyond the name that was already known.
 pub async fn join_room(
     state: &State,
     room_code: &RoomCode,
     request: &CreateOrJoinRoomRequest,
+    metadata: &ClientMetadata,
 ) -> MuuzikaResult<RoomJoinedResponse> {
-    const LOG_TARGET: &'static str = "muuzika::lobby::join_room";
+    const LOG_TARGET: &str = "muuzika::lobby::join_room";
     let identifier = log_identifier!();
     let error_logger = create_error_logger!(LOG_TARGET, identifier, "Error joining room");
 
     log::debug!(target: LOG_TARGET, "{} | Joining room {}, {:?}", identifier, room_code, request);
 
+    if room_code.is_practice() {
+        return Err(error_logger(MuuzikaError::PracticeRoom {
+            room_code: room_code.clone(),
+        }));
+    }
+
+    let created_at = chrono::Utc::now().timestamp_millis() as u64;
+    let token = encode_token(&state.config.jwt, created_at, room_code, &request.username)
+        .map_err(error_logger)?;
+
     let wrapped_room = state
         .rooms
         .read()
         .await
-        .get(&room_code)
+        .get(room_code)
         .ok_or_else(|| MuuzikaError::RoomNotFound {
             room_code: room_code.clone(),
         })
         .map_err(error_logger)?
         .clone();
 
-    let token = {
+    let (token, nonce) = {
         let mut room = wrapped_room.write().await;
 
+        if room.cleaning_up {
+            return Err(error_logger(MuuzikaError::RoomClosing {
+                room_code: room_code.clone(),
+            }));
+        }
+
         if room.players.contains_key(&request.username) {
-            Err(MuuzikaError::UsernameTaken {
+            return Err(error_logger(MuuzikaError::UsernameTaken {
                 room_code: room_code.clone(),
                 username: request.username.clone(),
-            })
-            .map_err(error_logger)?;
+                suggestions: room.suggest_usernames(&request.username),
+            }));
         }
 
-        let player = Player::new(request.username.clone());
-        let token = encode_token(
-            &state.jwt_secret,
-            player.created_at,
-            &room_code,
-            &request.username,
-        )
-        .map_err(error_logger)?;
+        let mut player = Player::new(request.username.clone(), metadata.clone());
+        player.created_at = created_at;
+        let nonce = rotate_ws_nonce(state, &mut player);
         room.players.insert(request.username.clone(), player);
+        state.analytics.emit(AnalyticsEvent::PlayerJoined {
+            room_code: room_code.clone(),
+            player_count: room.players.len(),
+        });
 
         log::debug!(target: LOG_TARGET, "{} | Player {} joined room {} successfully", identifier, request.username, room_code);
         room.send(ServerMessage::PlayerJoined(request.username.clone()))
             .map_err(error_logger)?;
+        send_player_count(state, &room, None);
 
         if let Some(tx) = room.cancel_cleanup.take() {
             log::debug!(target: LOG_TARGET, "{} | Cancelling cleanup for room {}", identifier, room_code);
             let _ = tx.send(());
         }
 
-        token
+        (token, nonce)
     };
 
     schedule_player_cleanup(
@@ -121,66 +339,155 @@ pub async fn join_room(
     Ok(RoomJoinedResponse {
         room_code: room_code.clone(),
         token,
+        nonce,
     })
 }
 
+#[derive(Deserialize, Debug)]
+pub struct RoomListQuery {
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomListResponse {
+    pub rooms: Vec<PublicRoomDto>,
+    pub total: usize,
+}
+
+pub async fn list_public_rooms(state: &State, query: &RoomListQuery) -> RoomListResponse {
+    const DEFAULT_LIMIT: usize = 20;
+    const MAX_LIMIT: usize = 100;
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    // Don't hold the rooms map lock while waiting on each room's lock
+    let wrapped_rooms: Vec<WrappedRoom> = state.rooms.read().await.values().cloned().collect();
+
+    let mut rooms = Vec::new();
+    for wrapped_room in wrapped_rooms {
+        let room = wrapped_room.read().await;
+        if room.public {
+            rooms.push(PublicRoomDto::from(&room as &Room));
+        }
+    }
+    rooms.sort_by(|a, b| a.code.cmp(&b.code));
+
+    let total = rooms.len();
+    let rooms = rooms.into_iter().skip(query.offset).take(limit).collect();
+
+    RoomListResponse { rooms, total }
+}
+
 pub async fn connect_player(
     state: &State,
     token: &String,
+    nonce: Option<&str>,
     ws: &WsConnection,
+    metadata: &ClientMetadata,
 ) -> MuuzikaResult<(WrappedRoom, RoomSyncDto)> {
-    const LOG_TARGET: &'static str = "muuzika::lobby::connect_player";
+    const LOG_TARGET: &str = "muuzika::lobby::connect_player";
     let identifier = log_identifier!();
     let error_logger = create_error_logger!(LOG_TARGET, identifier, "Error connecting player");
 
     log::debug!(target: LOG_TARGET, "{} | Connecting player with token {}, {:?}", identifier, token, ws);
 
-    let claims = decode_token(&state.jwt_secret, &token).map_err(error_logger)?;
+    let claims = decode_token(&state.config.jwt, token).map_err(error_logger)?;
     log::debug!(target: LOG_TARGET, "{} | Decoded token: {:?}", identifier, claims);
 
-    let wrapped_room = state
-        .rooms
-        .read()
-        .await
-        .get(&claims.room_code)
-        .ok_or_else(|| MuuzikaError::RoomNotFound {
-            room_code: claims.room_code.clone(),
-        })
-        .map_err(error_logger)?
-        .clone();
+    // The token is trusted as-is, but the room it points to may have been cleaned up
+    // since it was issued, in which case the client has to create a new room
+    let wrapped_room = match state.rooms.read().await.get(&claims.room_code) {
+        Some(wrapped_room) => wrapped_room.clone(),
+        None => {
+            log::debug!(target: LOG_TARGET, "{} | Room {} from token no longer exists", identifier, claims.room_code);
+            return Err(MuuzikaError::RoomNotFound {
+                room_code: claims.room_code,
+            });
+        }
+    };
 
     let sync = {
         let mut room = wrapped_room.write().await;
 
-        let player = room
-            .get_player_mut(&claims.username)
-            .map_err(error_logger)?;
+        if room.cleaning_up {
+            log::debug!(target: LOG_TARGET, "{} | Room {} from token is being cleaned up", identifier, claims.room_code);
+            return Err(MuuzikaError::RoomClosing {
+                room_code: claims.room_code,
+            });
+        }
 
-        if claims.iat != player.created_at {
-            Err(MuuzikaError::UsernameTaken {
-                room_code: claims.room_code.clone(),
-                username: claims.username.clone(),
-            })
-            .map_err(error_logger)?;
+        // The room still exists, but the player may have been evicted from it,
+        // in which case the client can still rejoin the same room
+        let player = match room.get_player_mut(&claims.username) {
+            Ok(player) if player.created_at == claims.iat => player,
+            Ok(_) => {
+                log::debug!(target: LOG_TARGET, "{} | Player \"{}\" was evicted from room {} and the username was taken since", identifier, claims.username, claims.room_code);
+                return Err(MuuzikaError::PlayerNotInRoom {
+                    room_code: claims.room_code,
+                    username: claims.username,
+                });
+            }
+            Err(e) => {
+                log::debug!(target: LOG_TARGET, "{} | Player \"{}\" was evicted from room {}", identifier, claims.username, claims.room_code);
+                return Err(e);
+            }
+        };
+
+        if state.config.require_ws_nonce && (nonce.is_none() || nonce != player.ws_nonce.as_deref())
+        {
+            log::debug!(target: LOG_TARGET, "{} | Player \"{}\" sent a missing or already used nonce", identifier, claims.username);
+            return Err(MuuzikaError::InvalidNonce);
         }
+        let next_nonce = rotate_ws_nonce(state, player);
+        let was_connected = player.ws.is_some();
 
         if let Some(old_ws) = &player.ws {
             log::debug!(target: LOG_TARGET, "{} | Player \"{}\" was connected in another client, closing old connection, old={:?}, new={:?}", identifier, claims.username, old_ws, ws);
-            old_ws.send_and_close(ServerMessage::Error(
-                MuuzikaError::ConnectedInAnotherDevice.into(),
-            ));
+            old_ws.send_and_close(MuuzikaError::ConnectedInAnotherDevice);
         }
 
+        // Whatever was broadcast during a brief disconnect goes out first, the sync
+        // that follows is sent by the caller once this returns. On a first connect
+        // the sync already has all of it
+        let mut outbox = player.take_outbox();
+        if player.disconnected_at.take().is_none() {
+            outbox.clear();
+        }
+        if !outbox.is_empty() {
+            log::debug!(target: LOG_TARGET, "{} | Flushing {} buffered messages to player \"{}\"", identifier, outbox.len(), claims.username);
+        }
+        outbox.into_iter().for_each(|message| {
+            ws.send_raw(message);
+        });
+
         player.ws = Some(ws.clone());
+        player.metadata = metadata.clone();
         let cancel_cleanup = player.cancel_cleanup.take();
+        let cancel_disconnect_broadcast = player.cancel_disconnect_broadcast.take();
+
+        // If the player is coming back before their disconnect was announced,
+        // the others never saw them leave, so there's nothing to announce either.
+        // A failed send means the timer already fired, and its broadcast may or may not
+        // have gone out, so the connect is announced to be safe
+        let cancelled = cancel_disconnect_broadcast.is_some_and(|tx| tx.send(()).is_ok());
+        if cancelled {
+            log::debug!(target: LOG_TARGET, "{} | Player \"{}\" reconnected quickly, cancelled disconnect broadcast", identifier, claims.username);
+        } else {
+            room.send_except(
+                ServerMessage::PlayerConnected(claims.username.clone()),
+                &claims.username,
+            )
+            .map_err(error_logger)?;
+        }
+        // Same for the count, which only dropped once the disconnect was announced
+        if !was_connected && !cancelled {
+            send_player_count(state, &room, Some(&claims.username));
+        }
 
-        room.send_except(
-            ServerMessage::PlayerConnected(claims.username.clone()),
-            &claims.username,
-        )
-        .map_err(error_logger)?;
-
-        log::debug!(target: LOG_TARGET, "{} | Player \"{}\" connected to room {} successfully", identifier, claims.username, room.code);
+        log::debug!(target: LOG_TARGET, "{} | Player \"{}\" connected to room {} successfully, user_agent={:?}, accept_language={:?}", identifier, claims.username, room.code, metadata.user_agent, metadata.accept_language);
 
         if let Some(tx) = cancel_cleanup {
             log::debug!(target: LOG_TARGET, "{} | Cancelling cleanup for player \"{}\"", identifier, claims.username);
@@ -189,7 +496,9 @@ pub async fn connect_player(
 
         RoomSyncDto {
             you: claims.username.clone(),
+            is_leader: room.leader == claims.username,
             room: (&room as &Room).into(),
+            next_nonce,
         }
     };
 
@@ -202,75 +511,194 @@ pub async fn disconnect_player(
     username: &Username,
     ws: &WsConnection,
 ) -> MuuzikaResult<()> {
-    const LOG_TARGET: &'static str = "muuzika::lobby::disconnect_player";
+    const LOG_TARGET: &str = "muuzika::lobby::disconnect_player";
     let identifier = log_identifier!();
     let error_logger = create_error_logger!(LOG_TARGET, identifier, "Error disconnecting player");
 
-    {
+    let disconnect_policy = {
         let mut room = wrapped_room.write().await;
         let player = room.get_player_mut(username).map_err(error_logger)?;
 
-        if let Some(old_ws) = &player.ws {
-            if old_ws != ws {
+        // The read loop and the dead connection reaper can both report the same
+        // connection, only the first one to get here disconnects the player
+        match &player.ws {
+            Some(current_ws) if current_ws.id == ws.id => {}
+            Some(_) => {
                 log::debug!(target: LOG_TARGET, "{} | Old connection of player \"{}\" was disconnected", identifier, username);
                 return Ok(());
             }
+            None => {
+                log::debug!(target: LOG_TARGET, "{} | Player \"{}\" was already disconnected", identifier, username);
+                return Ok(());
+            }
         }
 
         player.ws = None;
+        player.disconnected_at = Some(chrono::Utc::now().timestamp_millis() as u64);
+        room.disconnect_policy
+    };
 
-        room.send(ServerMessage::PlayerDisconnected(username.clone()))
-            .map_err(error_logger)?;
+    if disconnect_policy == DisconnectPolicy::RemoveImmediately {
+        log::debug!(target: LOG_TARGET, "{} | Room doesn't hold disconnected players, removing \"{}\" right away", identifier, username);
+        do_player_cleanup(state.clone(), wrapped_room.clone(), username.clone()).await;
+        return Ok(());
     }
 
+    schedule_disconnect_broadcast(state, wrapped_room.clone(), username.clone()).await;
     schedule_player_cleanup(state.clone(), wrapped_room.clone(), username.clone()).await;
 
     Ok(())
 }
 
+// Sends only borrow the room they're broadcasting from, so dead connections found
+// while sending are reported here and disconnected like any other connection
+pub async fn reap_dead_connections(state: State, mut rx: UnboundedReceiver<DeadConnection>) {
+    const LOG_TARGET: &str = "muuzika::lobby::reap_dead_connections";
+
+    while let Some(dead) = rx.recv().await {
+        let wrapped_room = match state.rooms.read().await.get(&dead.room_code) {
+            Some(wrapped_room) => wrapped_room.clone(),
+            None => continue,
+        };
+
+        log::debug!(target: LOG_TARGET, "Connection {:?} of player \"{}\" in room {} is dead, disconnecting", dead.ws, dead.username, dead.room_code);
+        let _ = disconnect_player(&state, &wrapped_room, &dead.username, &dead.ws).await;
+    }
+}
+
 async fn create_room_with_code(
     state: &State,
-    username: &Username,
+    request: &CreateOrJoinRoomRequest,
     room_code: &RoomCode,
+    metadata: &ClientMetadata,
 ) -> MuuzikaResult<RoomJoinedResponse> {
-    let leader = Player::new(username.clone());
-    let token = encode_token(&state.jwt_secret, leader.created_at, &room_code, username)?;
-    let room = Room::new(room_code.clone(), leader);
+    let username = &request.username;
+    let mut leader = Player::new(username.clone(), metadata.clone());
+    let token = encode_token(&state.config.jwt, leader.created_at, room_code, username)?;
+    let nonce = rotate_ws_nonce(state, &mut leader);
+    let mut room = Room::new(
+        room_code.clone(),
+        leader,
+        request.public,
+        state.dead_connections.clone(),
+        state.config.outbox_capacity,
+    );
+    room.disconnect_policy = request.disconnect_policy;
+    room.host = metadata.ip;
+    room.display_length = state.config.username_display_length;
+
+    // Nobody else can join, so there is nothing to hold a disconnected player's spot for
+    if room_code.is_practice() {
+        room.public = false;
+        room.host = None;
+        room.disconnect_policy = DisconnectPolicy::RemoveImmediately;
+    }
 
     let wrapped_room = Arc::new(RwLock::new(room));
 
-    state
-        .rooms
-        .write()
-        .await
-        .insert(room_code.clone(), wrapped_room.clone());
+    // Everything that can fail happens before this, so a room is only ever inserted
+    // when it's complete, and an existing room is never silently replaced
+    match state.rooms.write().await.entry(room_code.clone()) {
+        Entry::Occupied(_) => {
+            return Err(MuuzikaError::RoomCodeInUse {
+                room_code: room_code.clone(),
+            })
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(wrapped_room.clone());
+        }
+    }
 
     schedule_player_cleanup(state.clone(), wrapped_room, username.clone()).await;
 
     Ok(RoomJoinedResponse {
         room_code: room_code.clone(),
         token,
+        nonce,
     })
 }
 
-async fn pop_room_code(state: &State) -> MuuzikaResult<(RoomCode, usize)> {
-    let mut available_codes = state.available_codes.write().await;
-    available_codes
+// The timer only holds a weak reference, so it doesn't keep an entry that was already
+// replaced alive (and with it the sender that would cancel the timer)
+fn schedule_idempotency_key_eviction(
+    state: State,
+    key: String,
+    entry: &Arc<IdempotentCreate>,
+    rx: oneshot::Receiver<()>,
+) {
+    let duration = state.config.idempotency_key_ttl;
+    let entry = Arc::downgrade(entry);
+
+    let context = format!("eviction of idempotency key \"{}\"", key);
+    state.clone().timers.schedule(duration, rx, move || {
+        spawn_supervised(context, async move {
+            if let Some(entry) = entry.upgrade() {
+                remove_idempotency_key(&state, &key, &entry).await;
+            }
+        });
+    });
+}
+
+// When required, a token alone isn't enough to connect: each connection also spends
+// a single-use nonce, and the next one is only handed out over the connection itself,
+// so someone who captured a token (and even a used nonce) can't replay it
+fn rotate_ws_nonce(state: &State, player: &mut Player) -> Option<String> {
+    if !state.config.require_ws_nonce {
+        return None;
+    }
+
+    let nonce = nanoid!();
+    player.ws_nonce = Some(nonce.clone());
+    Some(nonce)
+}
+
+async fn reserve_host_slot(state: &State, host: IpAddr) -> MuuzikaResult<()> {
+    let mut rooms_per_host = state.rooms_per_host.write().await;
+    let rooms = rooms_per_host.entry(host).or_insert(0);
+
+    if let Some(limit) = state.config.max_rooms_per_host {
+        if *rooms >= limit {
+            return Err(MuuzikaError::TooManyRooms { limit });
+        }
+    }
+
+    *rooms += 1;
+    Ok(())
+}
+
+async fn release_host_slot(state: &State, host: Option<IpAddr>) {
+    let host = match host {
+        Some(host) => host,
+        None => return,
+    };
+
+    let mut rooms_per_host = state.rooms_per_host.write().await;
+    if let Some(rooms) = rooms_per_host.get_mut(&host) {
+        *rooms = rooms.saturating_sub(1);
+        if *rooms == 0 {
+            rooms_per_host.remove(&host);
+        }
+    }
+}
+
+fn pop_room_code(state: &State) -> MuuzikaResult<(RoomCode, usize)> {
+    state
+        .available_codes
         .pop()
-        .map(|room_code| (room_code, available_codes.len()))
-        .ok_or_else(|| MuuzikaError::OutOfRoomCodes)
+        .ok_or(MuuzikaError::OutOfRoomCodes)
 }
 
-async fn push_room_code(state: &State, room_code: RoomCode) -> usize {
-    let mut available_codes = state.available_codes.write().await;
-    available_codes.push(room_code);
-    available_codes.len()
+fn push_room_code(state: &State, room_code: RoomCode) -> usize {
+    if room_code.is_practice() {
+        return state.available_codes.remaining();
+    }
+    state.available_codes.push(room_code)
 }
 
 async fn schedule_player_cleanup(state: State, wrapped_room: WrappedRoom, username: Username) {
-    const LOG_TARGET: &'static str = "muuzika::lobby::schedule_player_cleanup";
+    const LOG_TARGET: &str = "muuzika::lobby::schedule_player_cleanup";
 
-    let duration = Duration::from_secs(10);
+    let duration = state.config.player_cleanup_delay;
 
     let rx = {
         let mut room = wrapped_room.write().await;
@@ -287,15 +715,64 @@ async fn schedule_player_cleanup(state: State, wrapped_room: WrappedRoom, userna
         rx
     };
 
-    tokio::spawn(async move {
-        if let Err(_) = timeout(duration, rx).await {
-            do_player_cleanup(state, wrapped_room, username).await;
-        }
+    let context = format!("cleanup of player \"{}\"", username);
+    state.clone().timers.schedule(duration, rx, move || {
+        spawn_supervised(context, do_player_cleanup(state, wrapped_room, username));
+    });
+}
+
+// Mobile clients drop and reconnect all the time when backgrounded, so the disconnect
+// is only announced if the player doesn't come back within a short window
+async fn schedule_disconnect_broadcast(
+    state: &State,
+    wrapped_room: WrappedRoom,
+    username: Username,
+) {
+    const LOG_TARGET: &str = "muuzika::lobby::schedule_disconnect_broadcast";
+
+    let duration = state.config.disconnect_broadcast_delay;
+
+    let rx = {
+        let mut room = wrapped_room.write().await;
+        let player = if let Ok(p) = room.get_player_mut(&username) {
+            p
+        } else {
+            return;
+        };
+
+        let (tx, rx) = oneshot::channel::<()>();
+        player.cancel_disconnect_broadcast = Some(tx);
+        rx
+    };
+
+    let context = format!("disconnect broadcast of player \"{}\"", username);
+    let state = state.clone();
+    state.clone().timers.schedule(duration, rx, move || {
+        spawn_supervised(context, async move {
+            let mut room = wrapped_room.write().await;
+            let player = if let Ok(p) = room.get_player_mut(&username) {
+                p
+            } else {
+                return;
+            };
+
+            player.cancel_disconnect_broadcast = None;
+
+            // Reconnected after the timer fired but before this got the lock
+            if player.ws.is_some() {
+                log::debug!(target: LOG_TARGET, "Player \"{}\" reconnected in the meantime, not broadcasting disconnect", username);
+                return;
+            }
+
+            log::debug!(target: LOG_TARGET, "Player \"{}\" did not reconnect, broadcasting disconnect", username);
+            let _ = room.send(ServerMessage::PlayerDisconnected(username.clone()));
+            send_player_count(&state, &room, None);
+        });
     });
 }
 
 async fn do_player_cleanup(state: State, wrapped_room: WrappedRoom, username: Username) {
-    const LOG_TARGET: &'static str = "muuzika::lobby::do_player_cleanup";
+    const LOG_TARGET: &str = "muuzika::lobby::do_player_cleanup";
 
     let is_empty = {
         let mut room = wrapped_room.write().await;
@@ -311,38 +788,75 @@ async fn do_player_cleanup(state: State, wrapped_room: WrappedRoom, username: Us
             return;
         }
 
-        log::debug!(target: LOG_TARGET, "Player {} is disconnected, cleaning up", username);
-        room.players.remove(&username);
+        log::debug!(target: LOG_TARGET, "Player {} is disconnected, cleaning up, last_active_at={}", username, player.last_active_at());
+        let was_leader = room.leader == username;
+        room.remove_player(&username);
 
         let _ = room.send(ServerMessage::PlayerLeft(username.clone()));
+        send_player_count(&state, &room, None);
+        state.analytics.emit(AnalyticsEvent::PlayerLeft {
+            room_code: room.code.clone(),
+            player_count: room.players.len(),
+        });
+
+        if was_leader
+            && !room.players.is_empty()
+            && state.config.leader_disconnect_policy == LeaderDisconnectPolicy::CloseRoom
+        {
+            log::debug!(target: LOG_TARGET, "Player {} was the leader of room {}, closing it", username, room.code);
+            close_room(&state, &mut room).await;
+            return;
+        }
+
+        if was_leader && !room.players.is_empty() {
+            log::debug!(target: LOG_TARGET, "Player {} was the leader of room {}, {} is the new leader", username, room.code, room.leader);
+            let _ = room.send(ServerMessage::LeaderChanged(room.leader.clone()));
+        }
 
         room.players.is_empty()
     };
 
-    if is_empty {
+    if is_empty && wrapped_room.read().await.code.is_practice() {
+        do_room_cleanup(state, wrapped_room).await;
+    } else if is_empty {
         schedule_room_cleanup(state, wrapped_room.clone()).await;
     }
 }
 
-async fn schedule_room_cleanup(state: State, wrapped_room: WrappedRoom) {
-    const LOG_TARGET: &'static str = "muuzika::lobby::schedule_room_cleanup";
+pub async fn schedule_room_cleanup(state: State, wrapped_room: WrappedRoom) {
+    const LOG_TARGET: &str = "muuzika::lobby::schedule_room_cleanup";
 
-    let duration = Duration::from_secs(10);
+    let duration = state.config.room_cleanup_delay;
 
     log::debug!(target: LOG_TARGET, "Scheduling cleanup for room {} in {} seconds", wrapped_room.read().await.code, duration.as_secs());
 
+    let (room_code, host) = {
+        let room = wrapped_room.read().await;
+        (room.code.clone(), room.host)
+    };
     let (tx, rx) = oneshot::channel::<()>();
     wrapped_room.write().await.cancel_cleanup = Some(tx);
-    tokio::spawn(async move {
-        if let Err(_) = timeout(duration, rx).await {
-            do_room_cleanup(state, wrapped_room).await;
-        }
+
+    // If the cleanup itself panics, the room would be stuck in the state forever
+    // and its code would never go back to the pool, so drop it unconditionally
+    let fallback_state = state.clone();
+    state.clone().timers.schedule(duration, rx, move || {
+        spawn_supervised_with_fallback(
+            format!("cleanup of room {}", room_code),
+            do_room_cleanup(state, wrapped_room),
+            async move {
+                remove_room(&fallback_state, &room_code, host).await;
+            },
+        );
     });
 }
 
 async fn do_room_cleanup(state: State, wrapped_room: WrappedRoom) {
-    const LOG_TARGET: &'static str = "muuzika::lobby::do_room_cleanup";
-    let room = wrapped_room.read().await;
+    const LOG_TARGET: &str = "muuzika::lobby::do_room_cleanup";
+
+    // The lock is held until the room is out of the state, so a join or connect either
+    // gets in before (and the room is kept) or sees `cleaning_up` and backs off
+    let mut room = wrapped_room.write().await;
 
     if !room.players.is_empty() {
         log::debug!(target: LOG_TARGET, "Room {} is not empty, will not clean up", room.code);
@@ -350,6 +864,52 @@ async fn do_room_cleanup(state: State, wrapped_room: WrappedRoom) {
     }
 
     log::debug!(target: LOG_TARGET, "Room {} is empty, cleaning up", room.code);
-    state.rooms.write().await.remove(&room.code);
-    push_room_code(&state, room.code.clone()).await;
+    room.cleaning_up = true;
+    remove_room(&state, &room.code, room.host).await;
+}
+
+// The player that just connected gets the full roster in the sync instead
+fn send_player_count(state: &State, room: &Room, except: Option<&Username>) {
+    if !state.config.broadcast_player_count {
+        return;
+    }
+
+    let message = ServerMessage::PlayerCountChanged {
+        connected: room.players.values().filter(|p| p.ws.is_some()).count(),
+        total: room.players.len(),
+    };
+    let _ = match except {
+        Some(except) => room.send_except(message, except),
+        None => room.send(message),
+    };
+}
+
+// Kicks everyone still in the room, dropping the players also cancels their timers
+async fn close_room(state: &State, room: &mut Room) {
+    for player in room.players.values() {
+        if let Some(ws) = &player.ws {
+            ws.send_and_close(MuuzikaError::LeaderLeft {
+                room_code: room.code.clone(),
+            });
+        }
+    }
+
+    room.players.clear();
+    room.cleaning_up = true;
+    remove_room(state, &room.code, room.host).await;
+}
+
+async fn remove_room(state: &State, room_code: &RoomCode, host: Option<IpAddr>) {
+    if state.rooms.write().await.remove(room_code).is_none() {
+        return;
+    }
+
+    // Bookkeeping first, once the room is out of the map a panic past this point
+    // can't be recovered by the cleanup fallback anymore
+    release_host_slot(state, host).await;
+    push_room_code(state, room_code.clone());
+
+    state.analytics.emit(AnalyticsEvent::RoomClosed {
+        room_code: room_code.clone(),
+    });
 }
@@ -5,9 +5,14 @@ use std::env;
 use warp::Filter;
 
 use crate::filters::{filters, handle_rejection};
+use crate::helpers::get_env_or_default;
+use crate::serialization::{set_timestamp_format, TimestampFormat};
 use crate::state::State;
 
+mod admin;
+mod analytics;
 mod auth;
+mod config;
 mod errors;
 mod filters;
 #[macro_use]
@@ -17,6 +22,9 @@ mod messages;
 mod rooms;
 mod serialization;
 mod state;
+#[cfg(test)]
+mod tests;
+mod timers;
 mod ws;
 
 #[tokio::main]
@@ -26,6 +34,10 @@ async fn main() {
     }
     pretty_env_logger::init_timed();
 
+    set_timestamp_format(get_env_or_default(
+        "TIMESTAMP_FORMAT",
+        TimestampFormat::default(),
+    ));
     let state = State::new();
     let server = filters(state)
         .recover(handle_rejection)
@@ -1,8 +1,9 @@
 use serde::{Deserialize, Serialize};
 
 use crate::errors::{ErrorResponse, MuuzikaResult};
-use crate::rooms::{RoomSyncDto, Username};
+use crate::rooms::{Avatar, RoomCode, RoomSyncDto, Username};
 use crate::state::WrappedRoom;
+use crate::ws::{ConnectionId, WsConnection};
 
 #[derive(Serialize, Debug, Clone)]
 #[serde(tag = "type", content = "data")]
@@ -12,25 +13,64 @@ pub enum ServerMessage {
     PlayerLeft(Username),
     PlayerConnected(Username),
     PlayerDisconnected(Username),
+    LeaderChanged(Username),
+    // Cheaper than keeping count from the roster, only sent when enabled in the config
+    PlayerCountChanged {
+        connected: usize,
+        total: usize,
+    },
+    PlayerAvatarChanged {
+        username: Username,
+        avatar: Avatar,
+    },
     Noop,
     Error(ErrorResponse),
+    #[allow(dead_code)]
     Result(u32),
-    AddResult { result: u32, username: Username },
+    AddResult {
+        result: u32,
+        username: Username,
+    },
+    Announcement {
+        text: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Whoami {
+        username: Username,
+        room_code: RoomCode,
+        is_leader: bool,
+        connection_id: ConnectionId,
+    },
 }
 
+// Unknown fields are ignored everywhere (`ack` is one already), so newer clients can send
+// extra metadata to older servers. Don't add `deny_unknown_fields` unless a message would
+// be misread by ignoring a field, e.g. one that changes what it does
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", content = "data")]
 pub enum ClientMessage {
     Add(Vec<u32>),
+    Whoami,
+    Heartbeat,
+    SetAvatar { avatar: String },
 }
 
 pub async fn handle_client_message(
     message: ClientMessage,
+    conn: &WsConnection,
     username: &Username,
     room: &WrappedRoom,
 ) -> ServerMessage {
+    // Any message counts as activity, a heartbeat is just a message that does nothing else
+    if let Ok(player) = room.read().await.get_player(username) {
+        player.touch();
+    }
+
     let result: MuuzikaResult<ServerMessage> = match message {
         ClientMessage::Add(numbers) => handle_add(numbers, username, room).await,
+        ClientMessage::Whoami => handle_whoami(conn, username, room).await,
+        ClientMessage::Heartbeat => Ok(ServerMessage::Noop),
+        ClientMessage::SetAvatar { avatar } => handle_set_avatar(avatar, username, room).await,
     };
 
     result
@@ -52,3 +92,35 @@ pub async fn handle_add(
 
     Ok(ServerMessage::Noop)
 }
+
+pub async fn handle_whoami(
+    conn: &WsConnection,
+    username: &Username,
+    room: &WrappedRoom,
+) -> MuuzikaResult<ServerMessage> {
+    let room = room.read().await;
+
+    Ok(ServerMessage::Whoami {
+        username: username.clone(),
+        room_code: room.code.clone(),
+        is_leader: &room.leader == username,
+        connection_id: conn.id.clone(),
+    })
+}
+
+pub async fn handle_set_avatar(
+    avatar: String,
+    username: &Username,
+    room: &WrappedRoom,
+) -> MuuzikaResult<ServerMessage> {
+    let avatar = Avatar::try_from(avatar)?;
+
+    let mut room = room.write().await;
+    room.get_player_mut(username)?.avatar = Some(avatar.clone());
+    room.send(ServerMessage::PlayerAvatarChanged {
+        username: username.clone(),
+        avatar,
+    })?;
+
+    Ok(ServerMessage::Noop)
+}
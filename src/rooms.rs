@@ -1,27 +1,79 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
 use derive_more::{Display, FromStr};
+use nanoid::nanoid;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::oneshot;
+use warp::ws::Message;
 
 use crate::errors::{MuuzikaError, MuuzikaResult};
 use crate::ws;
 use crate::ws::WsConnection;
 
-#[derive(Serialize, Deserialize, Display, Debug, Clone, Eq, PartialEq, Hash, FromStr)]
+#[derive(
+    Serialize, Deserialize, Display, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, FromStr,
+)]
 pub struct RoomCode(String);
 
 impl RoomCode {
     pub fn new(code: String) -> Self {
         Self(code)
     }
+
+    // Practice rooms get long random codes outside the pool, so they never use up
+    // one of the short codes and can't be guessed either
+    pub fn practice() -> Self {
+        Self(format!("{}{}", Self::PRACTICE_PREFIX, nanoid!(12)))
+    }
+
+    pub fn is_practice(&self) -> bool {
+        self.0.starts_with(Self::PRACTICE_PREFIX)
+    }
+
+    const PRACTICE_PREFIX: &'static str = "practice-";
 }
 
 pub struct Room {
     pub code: RoomCode,
     pub players: HashMap<Username, Player>,
     pub leader: Username,
+    pub public: bool,
     pub cancel_cleanup: Option<oneshot::Sender<()>>,
+    // Set once cleanup has committed to removing the room, anyone still holding
+    // on to it (e.g. a join that looked it up just before) must not use it anymore
+    pub cleaning_up: bool,
+    pub dead_connections: UnboundedSender<DeadConnection>,
+    pub disconnect_policy: DisconnectPolicy,
+    // Who created the room, counted against `Config::max_rooms_per_host`
+    pub host: Option<IpAddr>,
+    // Names longer than this are ellipsized when sent for display
+    pub display_length: usize,
+    // How many broadcasts are kept for a disconnected player until they reconnect, 0 disables it
+    pub outbox_capacity: usize,
+}
+
+// Casual public rooms may prefer dropping players as soon as they disconnect,
+// instead of holding their spot until the cleanup grace runs out
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisconnectPolicy {
+    #[default]
+    HoldForReconnect,
+    RemoveImmediately,
+}
+
+// A connection whose channel turned out to be closed while sending to it,
+// meaning its flusher is gone and the player is effectively disconnected
+#[derive(Debug)]
+pub struct DeadConnection {
+    pub room_code: RoomCode,
+    pub username: Username,
+    pub ws: WsConnection,
 }
 
 impl Drop for Room {
@@ -31,7 +83,13 @@ impl Drop for Room {
 }
 
 impl Room {
-    pub fn new(code: RoomCode, leader: Player) -> Self {
+    pub fn new(
+        code: RoomCode,
+        leader: Player,
+        public: bool,
+        dead_connections: UnboundedSender<DeadConnection>,
+        outbox_capacity: usize,
+    ) -> Self {
         let mut players = HashMap::new();
         let leader_username = leader.username.clone();
         players.insert(leader_username.clone(), leader);
@@ -39,8 +97,72 @@ impl Room {
             code,
             players,
             leader: leader_username,
+            public,
             cancel_cleanup: None,
+            cleaning_up: false,
+            disconnect_policy: DisconnectPolicy::default(),
+            host: None,
+            display_length: Username::MAX_LENGTH,
+            dead_connections,
+            outbox_capacity,
+        }
+    }
+
+    // Leadership goes to whoever has been in the room the longest,
+    // so the room is never left with a leader that isn't in it
+    pub fn remove_player(&mut self, username: &Username) -> Option<Player> {
+        let player = self.players.remove(username)?;
+
+        if &self.leader == username {
+            self.elect_leader();
+        }
+
+        Some(player)
+    }
+
+    // Suffixes are random rather than counting up from 2, a sequence with gaps in it
+    // would tell anyone who asks which other names are already in the room
+    pub fn suggest_usernames(&self, taken: &Username) -> Vec<Username> {
+        const SUGGESTIONS: usize = 3;
+        const ATTEMPTS: usize = 20;
+
+        let mut rng = rand::thread_rng();
+        let mut suggestions: Vec<Username> = Vec::with_capacity(SUGGESTIONS);
+        for _ in 0..ATTEMPTS {
+            if suggestions.len() == SUGGESTIONS {
+                break;
+            }
+
+            // Suffixes replace the end of names that are already as long as they can be
+            let suffix = rng.gen_range(1000..10000).to_string();
+            let max_base = Username::MAX_LENGTH.saturating_sub(suffix.len());
+            let base: String = taken.0.chars().take(max_base).collect();
+            let Ok(username) = Username::try_new(format!("{}{}", base, suffix)) else {
+                continue;
+            };
+
+            if !self.players.contains_key(&username) && !suggestions.contains(&username) {
+                suggestions.push(username);
+            }
         }
+
+        suggestions
+    }
+
+    pub fn elect_leader(&mut self) {
+        if let Some(next) = self.players.values().min_by_key(|p| p.created_at) {
+            self.leader = next.username.clone();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn assert_invariants(&self) {
+        assert!(
+            self.players.is_empty() || self.players.contains_key(&self.leader),
+            "leader \"{}\" of room {} is not one of its players",
+            self.leader,
+            self.code
+        );
     }
 
     pub fn get_player_mut(&mut self, username: &Username) -> MuuzikaResult<&mut Player> {
@@ -61,41 +183,68 @@ impl Room {
             })
     }
 
-    fn send_base<T>(&self, message: T, except: Option<&Username>) -> MuuzikaResult<()>
+    pub fn send_to<T>(&self, message: T, target: BroadcastTarget) -> MuuzikaResult<()>
     where
         T: Serialize,
     {
         let message = ws::make_message(message, None)?;
+        self.broadcast_serialized(&message, target);
+        Ok(())
+    }
 
+    // Sends an already serialized message, so the same frame can be reused for
+    // every recipient (and across rooms) without serializing it again
+    // Returns how many players it was actually sent to, buffered messages don't count
+    pub fn broadcast_serialized(&self, message: &Message, target: BroadcastTarget) -> usize {
+        let mut sent = 0;
         self.players
             .values()
-            .filter_map(|player| {
-                if let Some(except) = except {
-                    if &player.username == except {
-                        return None;
+            .filter(|player| target.includes(player))
+            .for_each(|player| {
+                if let Some(ws) = &player.ws {
+                    if ws.send_raw(message.clone()) {
+                        sent += 1;
+                    } else {
+                        let _ = self.dead_connections.send(DeadConnection {
+                            room_code: self.code.clone(),
+                            username: player.username.clone(),
+                            ws: ws.clone(),
+                        });
                     }
+                } else if self.outbox_capacity > 0 && player.disconnected_at.is_some() {
+                    player.buffer(message.clone(), self.outbox_capacity);
                 }
-                player.ws.as_ref()
-            })
-            .for_each(|ws| {
-                ws.send_raw(message.clone());
             });
-
-        Ok(())
+        sent
     }
 
     pub fn send<T>(&self, message: T) -> MuuzikaResult<()>
     where
         T: Serialize,
     {
-        self.send_base(message, None)
+        self.send_to(message, BroadcastTarget::All)
     }
 
     pub fn send_except<T>(&self, message: T, except: &Username) -> MuuzikaResult<()>
     where
         T: Serialize,
     {
-        self.send_base(message, Some(except))
+        self.send_to(message, BroadcastTarget::Except(except))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BroadcastTarget<'a> {
+    All,
+    Except(&'a Username),
+}
+
+impl BroadcastTarget<'_> {
+    fn includes(&self, player: &Player) -> bool {
+        match self {
+            BroadcastTarget::All => true,
+            BroadcastTarget::Except(except) => &player.username != *except,
+        }
     }
 }
 
@@ -115,29 +264,152 @@ impl From<&Room> for RoomDto {
             players: room
                 .players
                 .values()
-                .map(|player| player.into())
+                .map(|player| PlayerDto::new(player, room.display_length))
                 .collect::<Vec<PlayerDto>>(),
         }
     }
 }
 
+// Listed publicly, so it must never carry usernames
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicRoomDto {
+    pub code: RoomCode,
+    pub player_count: usize,
+}
+
+impl From<&Room> for PublicRoomDto {
+    fn from(room: &Room) -> Self {
+        Self {
+            code: room.code.clone(),
+            player_count: room.players.len(),
+        }
+    }
+}
+
 #[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct RoomSyncDto {
     pub you: Username,
+    // Only valid as of this sync, later changes come as `LeaderChanged`
+    pub is_leader: bool,
     pub room: RoomDto,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_nonce: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Display, Debug, Clone, Eq, PartialEq, Hash, FromStr)]
+#[derive(Serialize, Deserialize, Display, Debug, Clone, Eq, PartialEq, Hash)]
+#[serde(try_from = "String")]
 pub struct Username(String);
+
+impl Username {
+    pub const MAX_LENGTH: usize = 24;
+
+    pub fn try_new(username: String) -> MuuzikaResult<Self> {
+        let trimmed = username.trim();
+        let reason = if trimmed.is_empty() {
+            Some("must not be empty")
+        } else if trimmed.chars().count() > Self::MAX_LENGTH {
+            Some("is too long")
+        } else if trimmed.chars().any(char::is_control) {
+            Some("must not contain control characters")
+        } else {
+            None
+        };
+
+        match reason {
+            Some(reason) => Err(MuuzikaError::InvalidUsername { username, reason }),
+            None => Ok(Self(trimmed.to_string())),
+        }
+    }
+
+    pub fn display(&self, max_length: usize) -> String {
+        if self.0.chars().count() <= max_length {
+            return self.0.clone();
+        }
+
+        let mut display: String = self.0.chars().take(max_length.saturating_sub(1)).collect();
+        display.push('…');
+        display
+    }
+}
+
+impl TryFrom<String> for Username {
+    type Error = MuuzikaError;
+
+    fn try_from(username: String) -> MuuzikaResult<Self> {
+        Self::try_new(username)
+    }
+}
+
+impl TryFrom<&str> for Username {
+    type Error = MuuzikaError;
+
+    fn try_from(username: &str) -> MuuzikaResult<Self> {
+        Self::try_new(username.to_string())
+    }
+}
+
+impl FromStr for Username {
+    type Err = MuuzikaError;
+
+    fn from_str(username: &str) -> MuuzikaResult<Self> {
+        Self::try_new(username.to_string())
+    }
+}
+
 pub type Score = u32;
 
+#[derive(Serialize, Display, Debug, Clone, Eq, PartialEq)]
+pub struct Avatar(String);
+
+impl Avatar {
+    // Only these, so clients can rely on having something to draw for every avatar
+    pub const ALLOWED: &'static [&'static str] = &[
+        "🐶", "🐱", "🦊", "🐼", "🐸", "🐵", "🦉", "🐙", "🦄", "🐢", "🎸", "🎧", "🎤", "🎹", "🥁",
+        "🎷",
+    ];
+}
+
+impl TryFrom<String> for Avatar {
+    type Error = MuuzikaError;
+
+    fn try_from(avatar: String) -> MuuzikaResult<Self> {
+        if Self::ALLOWED.contains(&avatar.as_str()) {
+            Ok(Self(avatar))
+        } else {
+            Err(MuuzikaError::InvalidAvatar { avatar })
+        }
+    }
+}
+
+/// Client details kept for troubleshooting, only ever shown in the admin room dump.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientMetadata {
+    pub user_agent: Option<String>,
+    pub accept_language: Option<String>,
+    // The peer address, so behind a proxy this is the proxy's
+    pub ip: Option<IpAddr>,
+}
+
 pub struct Player {
     username: Username,
     score: Score,
+    pub avatar: Option<Avatar>,
     pub ws: Option<WsConnection>,
     pub created_at: u64,
+    // Atomic so any message can bump it under the room's read lock
+    last_active_at: AtomicU64,
     pub cancel_cleanup: Option<oneshot::Sender<()>>,
+    pub cancel_disconnect_broadcast: Option<oneshot::Sender<()>>,
+    pub metadata: ClientMetadata,
+    pub ws_nonce: Option<String>,
+    // Only set while a player who has been connected is away, players who joined but
+    // never connected get everything in their first sync instead of the outbox
+    pub disconnected_at: Option<u64>,
+    // Behind a mutex since broadcasts only borrow the room
+    outbox: Mutex<VecDeque<Message>>,
 }
 
 impl Drop for Player {
@@ -147,30 +419,66 @@ impl Drop for Player {
 }
 
 impl Player {
-    pub fn new(username: Username) -> Self {
+    pub fn new(username: Username, metadata: ClientMetadata) -> Self {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
         Self {
             username,
             ws: None,
             score: 0,
-            created_at: chrono::Utc::now().timestamp_millis() as u64,
+            avatar: None,
+            created_at: now,
+            last_active_at: AtomicU64::new(now),
             cancel_cleanup: None,
+            cancel_disconnect_broadcast: None,
+            metadata,
+            ws_nonce: None,
+            disconnected_at: None,
+            outbox: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn buffer(&self, message: Message, capacity: usize) {
+        let mut outbox = self.outbox.lock().unwrap();
+        if outbox.len() >= capacity {
+            outbox.pop_front();
         }
+        outbox.push_back(message);
+    }
+
+    pub fn take_outbox(&self) -> VecDeque<Message> {
+        std::mem::take(&mut *self.outbox.lock().unwrap())
+    }
+
+    pub fn touch(&self) {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        self.last_active_at.fetch_max(now, Ordering::Relaxed);
+    }
+
+    pub fn last_active_at(&self) -> u64 {
+        self.last_active_at.load(Ordering::Relaxed)
     }
 }
 
 #[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayerDto {
+    // The full name is what clients must send back to refer to the player,
+    // `display_name` is only for showing
     pub username: Username,
+    pub display_name: String,
     pub score: Score,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<Avatar>,
     pub is_online: bool,
 }
 
-impl From<&Player> for PlayerDto {
-    fn from(player: &Player) -> Self {
+impl PlayerDto {
+    pub fn new(player: &Player, display_length: usize) -> Self {
         Self {
             username: player.username.clone(),
+            display_name: player.username.display(display_length),
             score: player.score,
+            avatar: player.avatar.clone(),
             is_online: player.ws.is_some(),
         }
     }
@@ -1,6 +1,38 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+
 use serde::Serializer;
 use warp::http::StatusCode;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampFormat {
+    #[default]
+    Rfc3339,
+    EpochMillis,
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            "millis" | "epoch_millis" => Ok(TimestampFormat::EpochMillis),
+            _ => Err(format!("Unknown timestamp format: {}", s)),
+        }
+    }
+}
+
+// Serializers can't see the state, so the format is process-wide: it's read from the
+// environment once at startup instead of living in the per-State config
+static TIMESTAMP_FORMAT: OnceLock<TimestampFormat> = OnceLock::new();
+
+pub fn set_timestamp_format(format: TimestampFormat) {
+    if TIMESTAMP_FORMAT.set(format).is_err() && TIMESTAMP_FORMAT.get() != Some(&format) {
+        log::warn!(target: "muuzika::serialization", "Timestamp format was already set, ignoring {:?}", format);
+    }
+}
+
 pub fn serialize_status_code<S>(code: &StatusCode, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -15,5 +47,20 @@ pub fn serialize_utc_date_time<S>(
 where
     S: Serializer,
 {
-    serializer.serialize_str(&date_time.to_rfc3339())
+    let format = TIMESTAMP_FORMAT.get().copied().unwrap_or_default();
+    serialize_utc_date_time_as(format, date_time, serializer)
+}
+
+pub fn serialize_utc_date_time_as<S>(
+    format: TimestampFormat,
+    date_time: &chrono::DateTime<chrono::Utc>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match format {
+        TimestampFormat::Rfc3339 => serializer.serialize_str(&date_time.to_rfc3339()),
+        TimestampFormat::EpochMillis => serializer.serialize_i64(date_time.timestamp_millis()),
+    }
 }
@@ -1,35 +1,117 @@
 use rand::seq::SliceRandom;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::fs::OpenOptions;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
 
-use crate::helpers::{get_env_or_default, get_env_or_panic};
+use crate::helpers::spawn_supervised;
 use rand::thread_rng;
-use tokio::sync::RwLock;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{mpsc, RwLock};
 
-use crate::rooms::{Room, RoomCode};
+use crate::analytics::{EventSink, FanoutSink, JsonLinesSink, NoopSink, WebhookSink};
+use crate::config::{Config, RoomCodeMode};
+use crate::lobby;
+use crate::lobby::IdempotentCreate;
+use crate::rooms::{DeadConnection, Room, RoomCode};
+use crate::timers::Timers;
 
 #[derive(Clone)]
 pub struct State {
-    pub jwt_secret: String,
+    pub config: Arc<Config>,
     pub rooms: Arc<RwLock<HashMap<RoomCode, WrappedRoom>>>,
-    pub available_codes: Arc<RwLock<Vec<RoomCode>>>,
+    pub available_codes: Arc<CodePool>,
+    pub analytics: Arc<dyn EventSink>,
+    pub idempotency_keys: Arc<RwLock<HashMap<String, Arc<IdempotentCreate>>>>,
+    pub dead_connections: UnboundedSender<DeadConnection>,
+    pub timers: Timers,
+    pub rooms_per_host: Arc<RwLock<HashMap<IpAddr, usize>>>,
 }
 
 pub type WrappedRoom = Arc<RwLock<Room>>;
 
+// Every create goes through here, so it's a plain mutex rather than an async lock: a pop or
+// push is all that ever happens under it, and it's never held across an await
+pub struct CodePool(Mutex<Vec<RoomCode>>);
+
+impl CodePool {
+    // Along with how many are left after it
+    pub fn pop(&self) -> Option<(RoomCode, usize)> {
+        let mut codes = self.0.lock().unwrap();
+        codes.pop().map(|room_code| (room_code, codes.len()))
+    }
+
+    pub fn push(&self, room_code: RoomCode) -> usize {
+        let mut codes = self.0.lock().unwrap();
+        codes.push(room_code);
+        codes.len()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
 impl State {
     pub fn new() -> Self {
-        let code_length = get_env_or_default("ROOM_CODE_LENGTH", 4);
-        let available_codes = generate_available_codes(code_length);
-        Self {
-            jwt_secret: get_env_or_panic("JWT_SECRET"),
+        Self::with_config(Config::from_env())
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        let available_codes =
+            generate_available_codes(config.room_code_length, config.room_code_mode);
+        let analytics = create_event_sink(&config);
+        let (dead_connections, dead_connections_rx) = mpsc::unbounded_channel();
+        let timers = Timers::new(config.timer_shards);
+        let state = Self {
+            config: Arc::new(config),
             rooms: Arc::new(RwLock::new(HashMap::new())),
-            available_codes: Arc::new(RwLock::new(available_codes)),
+            available_codes: Arc::new(CodePool(Mutex::new(available_codes))),
+            analytics,
+            idempotency_keys: Arc::new(RwLock::new(HashMap::new())),
+            dead_connections,
+            timers,
+            rooms_per_host: Arc::new(RwLock::new(HashMap::new())),
+        };
+
+        spawn_supervised(
+            "dead connection reaper".to_string(),
+            lobby::reap_dead_connections(state.clone(), dead_connections_rx),
+        );
+
+        state
+    }
+}
+
+fn create_event_sink(config: &Config) -> Arc<dyn EventSink> {
+    let mut sinks: Vec<Arc<dyn EventSink>> = Vec::new();
+
+    if let Some(url) = &config.analytics_webhook_url {
+        sinks.push(Arc::new(WebhookSink::new(url.clone())));
+    }
+
+    match config.event_log_path.as_deref() {
+        Some("-") => sinks.push(Arc::new(JsonLinesSink::new(io::stdout()))),
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .unwrap_or_else(|e| panic!("Could not open event log {}: {}", path, e));
+            sinks.push(Arc::new(JsonLinesSink::new(file)));
         }
+        None => {}
+    }
+
+    match sinks.len() {
+        0 => Arc::new(NoopSink),
+        1 => sinks.remove(0),
+        _ => Arc::new(FanoutSink(sinks)),
     }
 }
 
-fn generate_available_codes(code_length: u8) -> Vec<RoomCode> {
+fn generate_available_codes(code_length: u8, mode: RoomCodeMode) -> Vec<RoomCode> {
     if code_length > 9 {
         panic!("Room code cannot be longer than 9 characters");
     }
@@ -38,6 +120,11 @@ fn generate_available_codes(code_length: u8) -> Vec<RoomCode> {
     let mut codes: Vec<RoomCode> = (0..number_of_codes)
         .map(|c| RoomCode::new(format!("{:0width$}", c, width = code_length as usize)))
         .collect();
-    codes.shuffle(&mut thread_rng());
+
+    // Codes are popped from the end
+    match mode {
+        RoomCodeMode::Random => codes.shuffle(&mut thread_rng()),
+        RoomCodeMode::Sequential => codes.reverse(),
+    }
     codes
 }
@@ -0,0 +1,175 @@
+use hyper::{Method, StatusCode};
+use serde_json::json;
+
+use crate::admin;
+use crate::lobby;
+use crate::rooms::{ClientMetadata, PlayerDto};
+use crate::tests::harness::{request, test_state, TestServer, ADMIN_TOKEN};
+
+#[tokio::test]
+async fn gc_hands_leaderless_rooms_to_the_oldest_player() {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+    let response = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+    lobby::join_room(&state, &response.room_code, &request("bob"), &metadata)
+        .await
+        .unwrap();
+
+    let wrapped_room = state.rooms.read().await[&response.room_code].clone();
+    {
+        // Both can be created within the same millisecond
+        let mut room = wrapped_room.write().await;
+        let alice_created_at = room
+            .get_player(&"alice".parse().unwrap())
+            .unwrap()
+            .created_at;
+        room.get_player_mut(&"bob".parse().unwrap())
+            .unwrap()
+            .created_at = alice_created_at + 1;
+        room.leader = "ghost".parse().unwrap();
+    }
+
+    let report = admin::collect_garbage(&state).await;
+
+    assert_eq!(report.rooms_scanned, 1);
+    assert_eq!(report.leaders_reassigned.len(), 1);
+    assert!(report.cleanups_scheduled.is_empty());
+    let room = wrapped_room.read().await;
+    assert_eq!(room.leader, "alice".parse().unwrap());
+    room.assert_invariants();
+}
+
+#[tokio::test]
+async fn gc_schedules_cleanup_of_forgotten_empty_rooms() {
+    let state = test_state();
+    let response = lobby::create_room(&state, &request("alice"), &ClientMetadata::default())
+        .await
+        .unwrap();
+
+    let wrapped_room = state.rooms.read().await[&response.room_code].clone();
+    wrapped_room.write().await.players.clear();
+
+    let report = admin::collect_garbage(&state).await;
+    assert_eq!(report.cleanups_scheduled, vec![response.room_code.clone()]);
+    assert!(wrapped_room.read().await.cancel_cleanup.is_some());
+
+    // Already scheduled, so a second pass leaves it alone
+    let report = admin::collect_garbage(&state).await;
+    assert!(report.cleanups_scheduled.is_empty());
+}
+
+#[tokio::test]
+async fn gc_endpoint_requires_the_admin_token() {
+    let server = TestServer::start().await;
+
+    let (status, body) = server
+        .admin_request(Method::POST, "/admin/gc", None, None)
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"], "AdminUnauthorized");
+
+    let (status, body) = server
+        .admin_request(Method::POST, "/admin/gc", Some(ADMIN_TOKEN), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        json!({ "roomsScanned": 0, "leadersReassigned": [], "cleanupsScheduled": [] })
+    );
+}
+
+#[tokio::test]
+async fn announcement_reaches_every_connected_player() {
+    let server = TestServer::start().await;
+    let (first_room, alice_token) = server.create_room("alice").await;
+    let (_, bob_token) = server.create_room("bob").await;
+    // Joined but never connected, so not reached
+    server.join_room(&first_room, "carol").await;
+
+    let mut alice = server.connect(&alice_token).await;
+    alice.recv_type("Sync").await;
+    let mut bob = server.connect(&bob_token).await;
+    bob.recv_type("Sync").await;
+
+    let (status, body) = server
+        .admin_request(
+            Method::POST,
+            "/admin/broadcast",
+            Some(ADMIN_TOKEN),
+            Some(json!({ "text": "Restarting in 5 minutes" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "playersReached": 2 }));
+
+    let announcement = json!({
+        "type": "Announcement",
+        "data": { "text": "Restarting in 5 minutes" }
+    });
+    assert_eq!(alice.recv_type("Announcement").await, announcement);
+    assert_eq!(bob.recv_type("Announcement").await, announcement);
+}
+
+#[tokio::test]
+async fn room_dump_shows_client_metadata_that_players_never_see() {
+    let server = TestServer::start().await;
+    let metadata = ClientMetadata {
+        user_agent: Some("test-agent/1.0".to_string()),
+        accept_language: Some("pt-BR".to_string()),
+        ip: Some("203.0.113.7".parse().unwrap()),
+    };
+    let response = lobby::create_room(&server.state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+    let path = format!("/admin/rooms/{}", response.room_code);
+
+    let (status, _) = server.admin_request(Method::GET, &path, None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, body) = server
+        .admin_request(Method::GET, &path, Some(ADMIN_TOKEN), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["players"][0]["username"], "alice");
+    assert_eq!(
+        body["players"][0]["metadata"],
+        json!({ "userAgent": "test-agent/1.0", "acceptLanguage": "pt-BR", "ip": "203.0.113.7" })
+    );
+
+    let rooms = server.state.rooms.read().await;
+    let room = rooms[&response.room_code].read().await;
+    let player = room.get_player(&"alice".parse().unwrap()).unwrap();
+    let dto = serde_json::to_value(PlayerDto::new(player, room.display_length)).unwrap();
+    assert!(dto.get("metadata").is_none());
+    assert!(!dto.to_string().contains("test-agent"));
+}
+
+#[tokio::test]
+async fn player_lookup_validates_the_username_in_the_path() {
+    let server = TestServer::start().await;
+    let response = lobby::create_room(&server.state, &request("alice"), &ClientMetadata::default())
+        .await
+        .unwrap();
+    let path = |username: &str| format!("/admin/rooms/{}/players/{}", response.room_code, username);
+
+    let (status, body) = server
+        .admin_request(Method::GET, &path("alice"), Some(ADMIN_TOKEN), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["username"], "alice");
+    assert_eq!(body["isOnline"], false);
+
+    let (status, body) = server
+        .admin_request(Method::GET, &path("bob"), Some(ADMIN_TOKEN), None)
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"], "PlayerNotInRoom");
+
+    let (status, body) = server
+        .admin_request(Method::GET, &path("%20"), Some(ADMIN_TOKEN), None)
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "InvalidUsername");
+}
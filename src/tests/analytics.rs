@@ -0,0 +1,126 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::analytics::{AnalyticsEvent, EventSink, JsonLinesSink};
+use crate::config::Config;
+use crate::lobby;
+use crate::rooms::ClientMetadata;
+use crate::state::State;
+use crate::tests::harness::{eventually, request, test_config};
+
+#[derive(Default)]
+struct CapturingSink(Mutex<Vec<AnalyticsEvent>>);
+
+impl EventSink for CapturingSink {
+    fn emit(&self, event: AnalyticsEvent) {
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn lines(&self) -> Vec<Value> {
+        let buffer = self.0.lock().unwrap();
+        String::from_utf8_lossy(&buffer)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn event_log_traces_a_room_from_creation_to_close() {
+    let mut state = State::with_config(Config {
+        player_cleanup_delay: Duration::from_millis(10),
+        room_cleanup_delay: Duration::from_millis(10),
+        ..test_config()
+    });
+    let buffer = SharedBuffer::default();
+    state.analytics = Arc::new(JsonLinesSink::new(buffer.clone()));
+
+    let metadata = ClientMetadata::default();
+    let response = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+    lobby::join_room(&state, &response.room_code, &request("bob"), &metadata)
+        .await
+        .unwrap();
+
+    // Nobody connects, so both players and then the room are cleaned up
+    eventually(|| async { buffer.lines().len() == 5 }).await;
+    let lines = buffer.lines();
+
+    let events: Vec<_> = lines.iter().map(|line| line["event"].clone()).collect();
+    assert_eq!(
+        events,
+        [
+            "RoomCreated",
+            "PlayerJoined",
+            "PlayerLeft",
+            "PlayerLeft",
+            "RoomClosed"
+        ]
+    );
+    let room_code = response.room_code.to_string();
+    assert!(lines
+        .iter()
+        .all(|line| line["data"]["roomCode"] == room_code));
+    assert!(lines.iter().all(|line| !line["timestamp"].is_null()));
+    assert_eq!(lines[3]["data"]["playerCount"], 0);
+}
+
+#[tokio::test]
+async fn lifecycle_events_are_emitted_in_order() {
+    let mut state = State::with_config(Config {
+        player_cleanup_delay: Duration::from_millis(10),
+        room_cleanup_delay: Duration::from_millis(10),
+        ..test_config()
+    });
+    let sink = Arc::new(CapturingSink::default());
+    state.analytics = sink.clone();
+
+    let metadata = ClientMetadata::default();
+    let response = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+    lobby::join_room(&state, &response.room_code, &request("bob"), &metadata)
+        .await
+        .unwrap();
+
+    eventually(|| async { sink.0.lock().unwrap().len() == 5 }).await;
+    let events = sink.0.lock().unwrap();
+    let room_code = &response.room_code;
+    assert!(
+        matches!(&events[0], AnalyticsEvent::RoomCreated { room_code: code } if code == room_code)
+    );
+    assert!(matches!(
+        &events[1],
+        AnalyticsEvent::PlayerJoined { room_code: code, player_count: 2 } if code == room_code
+    ));
+    assert!(matches!(
+        &events[2],
+        AnalyticsEvent::PlayerLeft { room_code: code, player_count: 1 } if code == room_code
+    ));
+    assert!(matches!(
+        &events[3],
+        AnalyticsEvent::PlayerLeft { room_code: code, player_count: 0 } if code == room_code
+    ));
+    assert!(
+        matches!(&events[4], AnalyticsEvent::RoomClosed { room_code: code } if code == room_code)
+    );
+}
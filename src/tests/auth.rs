@@ -0,0 +1,72 @@
+use crate::auth::{decode_token, encode_token, JwtConfig};
+use crate::errors::MuuzikaError;
+use crate::rooms::{RoomCode, Username};
+
+fn jwt_config(issuer: Option<&str>, audience: Option<&str>) -> JwtConfig {
+    JwtConfig {
+        secret: "secret".to_string(),
+        issuer: issuer.map(str::to_string),
+        audience: audience.map(str::to_string),
+    }
+}
+
+fn token_for(config: &JwtConfig) -> String {
+    encode_token(
+        config,
+        0,
+        &RoomCode::new("0000".to_string()),
+        &Username::try_new("alice".to_string()).unwrap(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn token_with_matching_issuer_and_audience_is_accepted() {
+    let config = jwt_config(Some("muuzika"), Some("players"));
+
+    let claims = decode_token(&config, &token_for(&config)).unwrap();
+
+    assert_eq!(claims.iss.as_deref(), Some("muuzika"));
+    assert_eq!(claims.aud.as_deref(), Some("players"));
+    assert_eq!(claims.username.to_string(), "alice");
+}
+
+#[test]
+fn token_with_wrong_issuer_is_invalid() {
+    let config = jwt_config(Some("muuzika"), None);
+    let token = token_for(&jwt_config(Some("someone-else"), None));
+
+    assert!(matches!(
+        decode_token(&config, &token),
+        Err(MuuzikaError::TokenInvalid)
+    ));
+}
+
+#[test]
+fn token_with_wrong_audience_is_invalid() {
+    let config = jwt_config(None, Some("players"));
+    let token = token_for(&jwt_config(None, Some("admins")));
+
+    assert!(matches!(
+        decode_token(&config, &token),
+        Err(MuuzikaError::TokenInvalid)
+    ));
+}
+
+#[test]
+fn token_without_the_configured_claims_is_invalid() {
+    let config = jwt_config(Some("muuzika"), Some("players"));
+    let token = token_for(&jwt_config(None, None));
+
+    assert!(matches!(
+        decode_token(&config, &token),
+        Err(MuuzikaError::TokenInvalid)
+    ));
+}
+
+#[test]
+fn issuer_and_audience_are_not_checked_when_unset() {
+    let token = token_for(&jwt_config(Some("someone-else"), Some("admins")));
+
+    assert!(decode_token(&jwt_config(None, None), &token).is_ok());
+}
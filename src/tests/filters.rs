@@ -0,0 +1,143 @@
+use hyper::{Method, StatusCode};
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+use warp::Filter;
+
+use crate::filters::{error_reply, handle_rejection, username_param};
+use crate::tests::harness::TestServer;
+
+#[tokio::test]
+async fn form_encoded_body_is_rejected_with_guidance() {
+    let server = TestServer::start().await;
+
+    let (status, body) = server
+        .request_raw(
+            Method::POST,
+            "/rooms",
+            Some("application/x-www-form-urlencoded"),
+            "username=alice".to_string(),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(body["error"], "UnsupportedContentType");
+    assert_eq!(
+        body["data"]["contentType"],
+        "application/x-www-form-urlencoded"
+    );
+    assert!(body["message"]
+        .as_str()
+        .unwrap()
+        .contains("application/json"));
+}
+
+#[tokio::test]
+async fn json_content_type_with_charset_is_accepted() {
+    let server = TestServer::start().await;
+
+    let (status, _) = server
+        .request_raw(
+            Method::POST,
+            "/rooms",
+            Some("application/json; charset=utf-8"),
+            r#"{"username":"alice"}"#.to_string(),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn body_without_a_content_type_is_read_as_json() {
+    let server = TestServer::start().await;
+
+    let (status, body) = server
+        .request_raw(
+            Method::POST,
+            "/rooms",
+            None,
+            r#"{"username":"alice"}"#.to_string(),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::CREATED);
+    assert!(body["token"].is_string());
+}
+
+async fn get_username(path: &str) -> (StatusCode, Value) {
+    let route = warp::path("players")
+        .and(username_param())
+        .and(warp::path::end())
+        .map(|username| warp::reply::json(&username))
+        .recover(handle_rejection);
+
+    let response = warp::test::request().path(path).reply(&route).await;
+    let body = serde_json::from_slice(response.body()).unwrap();
+    (response.status(), body)
+}
+
+#[tokio::test]
+async fn valid_username_path_segment_is_extracted() {
+    let (status, body) = get_username("/players/alice").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "alice");
+}
+
+#[tokio::test]
+async fn username_path_segment_is_percent_decoded() {
+    let (status, body) = get_username("/players/ana%20mar%C3%ADa").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "ana maría");
+}
+
+#[tokio::test]
+async fn invalid_username_path_segment_is_a_bad_request() {
+    let too_long = format!("/players/{}", "a".repeat(25));
+    let (status, body) = get_username(&too_long).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "InvalidUsername");
+    assert_eq!(body["data"]["reason"], "is too long");
+}
+
+#[tokio::test]
+async fn invalid_username_in_body_is_reported_by_field() {
+    let server = TestServer::start().await;
+
+    let (status, body) = server
+        .request(
+            Method::POST,
+            "/rooms",
+            Some(json!({ "username": "a".repeat(25) })),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "ValidationFailed");
+    assert_eq!(
+        body["data"]["fields"],
+        json!([{ "field": "username", "reason": "is too long" }])
+    );
+}
+
+struct Unserializable;
+
+impl Serialize for Unserializable {
+    fn serialize<S: Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+        Err(serde::ser::Error::custom("nope"))
+    }
+}
+
+#[tokio::test]
+async fn error_reply_falls_back_to_a_fixed_body() {
+    let response = error_reply(&Unserializable, StatusCode::BAD_REQUEST);
+
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], 500);
+    assert_eq!(body["error"], "Unknown");
+}
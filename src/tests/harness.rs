@@ -0,0 +1,259 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use hyper::http::request;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use warp::Filter;
+
+use crate::auth::JwtConfig;
+use crate::config::{Config, LeaderDisconnectPolicy, RoomCodeMode};
+use crate::filters::{filters, handle_rejection};
+use crate::lobby::CreateOrJoinRoomRequest;
+use crate::rooms::DisconnectPolicy;
+use crate::state::State;
+use crate::ws::{ConnectionId, WsConnection};
+
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
+pub fn test_config() -> Config {
+    Config {
+        jwt: JwtConfig {
+            secret: "test-secret".to_string(),
+            issuer: None,
+            audience: None,
+        },
+        room_code_length: 4,
+        room_code_mode: RoomCodeMode::Random,
+        analytics_webhook_url: None,
+        require_ws_nonce: false,
+        player_cleanup_delay: Duration::from_secs(10),
+        room_cleanup_delay: Duration::from_secs(10),
+        disconnect_broadcast_delay: Duration::from_secs(2),
+        idempotency_key_ttl: Duration::from_secs(60),
+        outbox_capacity: 0,
+        admin_token: Some(ADMIN_TOKEN.to_string()),
+        timer_shards: 1,
+        username_display_length: 16,
+        max_rooms_per_host: None,
+        leader_disconnect_policy: LeaderDisconnectPolicy::default(),
+        event_log_path: None,
+        message_dedupe_window: Duration::from_secs(10),
+        broadcast_player_count: false,
+    }
+}
+
+pub fn test_state() -> State {
+    State::with_config(test_config())
+}
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub state: State,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::with_state(test_state()).await
+    }
+
+    pub async fn with_state(state: State) -> Self {
+        let routes = filters(state.clone()).recover(handle_rejection);
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        Self { addr, state }
+    }
+
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        match body {
+            Some(body) => {
+                self.request_raw(method, path, Some("application/json"), body.to_string())
+                    .await
+            }
+            None => self.request_raw(method, path, None, String::new()).await,
+        }
+    }
+
+    pub async fn request_raw(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: Option<&str>,
+        body: String,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", self.addr, path));
+
+        if let Some(content_type) = content_type {
+            request = request.header("content-type", content_type);
+        }
+
+        self.send(request, body).await
+    }
+
+    pub async fn admin_request(
+        &self,
+        method: Method,
+        path: &str,
+        admin_token: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", self.addr, path));
+
+        if let Some(admin_token) = admin_token {
+            request = request.header("authorization", format!("Bearer {}", admin_token));
+        }
+
+        match body {
+            Some(body) => {
+                let request = request.header("content-type", "application/json");
+                self.send(request, body.to_string()).await
+            }
+            None => self.send(request, String::new()).await,
+        }
+    }
+
+    async fn send(&self, request: request::Builder, body: String) -> (StatusCode, Value) {
+        let body = Body::from(body);
+
+        let response = Client::new()
+            .request(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let value = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+
+        (status, value)
+    }
+
+    pub async fn create_room(&self, username: &str) -> (String, String) {
+        let (status, body) = self
+            .request(
+                Method::POST,
+                "/rooms",
+                Some(json!({ "username": username })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED, "{}", body);
+        room_joined(body)
+    }
+
+    pub async fn join_room(&self, room_code: &str, username: &str) -> (String, String) {
+        let (status, body) = self
+            .request(
+                Method::POST,
+                &format!("/rooms/{}", room_code),
+                Some(json!({ "username": username })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED, "{}", body);
+        room_joined(body)
+    }
+
+    pub async fn connect(&self, token: &str) -> TestClient {
+        let url = format!("ws://{}/ws?token={}", self.addr, token);
+        let (ws, _) = connect_async(url).await.unwrap();
+        TestClient { ws }
+    }
+}
+
+fn room_joined(body: Value) -> (String, String) {
+    (
+        body["roomCode"].as_str().unwrap().to_string(),
+        body["token"].as_str().unwrap().to_string(),
+    )
+}
+
+pub struct TestClient {
+    pub ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    pub async fn send(&mut self, message: Value) {
+        self.ws
+            .send(Message::text(message.to_string()))
+            .await
+            .unwrap();
+    }
+
+    pub async fn recv_frame(&mut self) -> Message {
+        timeout(RECV_TIMEOUT, self.ws.next())
+            .await
+            .expect("timed out waiting for a message")
+            .expect("connection ended")
+            .unwrap()
+    }
+
+    pub async fn recv(&mut self) -> Value {
+        loop {
+            match self.recv_frame().await {
+                Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                Message::Close(frame) => panic!("connection closed: {:?}", frame),
+                _ => continue,
+            }
+        }
+    }
+
+    pub async fn recv_type(&mut self, message_type: &str) -> Value {
+        loop {
+            let message = self.recv().await;
+            if message["type"] == message_type {
+                return message;
+            }
+        }
+    }
+}
+
+pub fn request(username: &str) -> CreateOrJoinRoomRequest {
+    CreateOrJoinRoomRequest {
+        username: username.parse().unwrap(),
+        public: false,
+        disconnect_policy: DisconnectPolicy::HoldForReconnect,
+        practice: false,
+    }
+}
+
+// A connection that isn't backed by a socket, along with the receiving end of its channel
+pub fn fake_connection() -> (WsConnection, UnboundedReceiver<warp::ws::Message>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let conn = WsConnection {
+        id: ConnectionId::generate(),
+        tx,
+        cancelled: Arc::new(Notify::new()),
+    };
+    (conn, rx)
+}
+
+pub async fn eventually<F, Fut>(mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    timeout(RECV_TIMEOUT, async {
+        while !condition().await {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("condition was not met in time");
+}
@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::timeout;
+
+use crate::analytics::{AnalyticsEvent, EventSink};
+use crate::config::Config;
+use crate::helpers::spawn_supervised_with_fallback;
+use crate::lobby;
+use crate::rooms::ClientMetadata;
+use crate::state::State;
+use crate::tests::harness::{eventually, request, test_config, test_state};
+
+#[tokio::test]
+async fn panicking_task_runs_its_fallback_and_frees_the_room_lock() {
+    let state = test_state();
+    let response = lobby::create_room(&state, &request("alice"), &ClientMetadata::default())
+        .await
+        .unwrap();
+    let wrapped_room = state.rooms.read().await[&response.room_code].clone();
+    let fell_back = Arc::new(AtomicBool::new(false));
+
+    let task_room = wrapped_room.clone();
+    let task_fell_back = fell_back.clone();
+    spawn_supervised_with_fallback(
+        "panicking test task".to_string(),
+        async move {
+            let _room = task_room.write().await;
+            panic!("deliberate panic while holding the room lock");
+        },
+        async move {
+            task_fell_back.store(true, Ordering::SeqCst);
+        },
+    );
+
+    eventually(|| async { fell_back.load(Ordering::SeqCst) }).await;
+    let _room = timeout(Duration::from_secs(1), wrapped_room.write())
+        .await
+        .expect("room lock is still held after the panic");
+}
+
+// Blows up in the middle of a room cleanup
+struct PanickingSink;
+
+impl EventSink for PanickingSink {
+    fn emit(&self, event: AnalyticsEvent) {
+        if let AnalyticsEvent::RoomClosed { .. } = event {
+            panic!("deliberate panic on {:?}", event);
+        }
+    }
+}
+
+#[tokio::test]
+async fn panicking_room_cleanup_still_releases_the_room_and_its_code() {
+    let mut state = State::with_config(Config {
+        room_code_length: 1,
+        player_cleanup_delay: Duration::from_millis(10),
+        room_cleanup_delay: Duration::from_millis(10),
+        ..test_config()
+    });
+    state.analytics = Arc::new(PanickingSink);
+
+    let response = lobby::create_room(&state, &request("alice"), &ClientMetadata::default())
+        .await
+        .unwrap();
+    let wrapped_room = state.rooms.read().await[&response.room_code].clone();
+    assert_eq!(state.available_codes.remaining(), 9);
+
+    eventually(|| async { state.rooms.read().await.is_empty() }).await;
+    eventually(|| async { state.available_codes.remaining() == 10 }).await;
+    let _room = timeout(Duration::from_secs(1), wrapped_room.write())
+        .await
+        .expect("room lock is still held after the panic");
+}
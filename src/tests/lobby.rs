@@ -0,0 +1,849 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use hyper::{Method, StatusCode};
+use serde_json::{json, Value};
+use tokio::time::{sleep, timeout};
+
+use crate::config::{Config, LeaderDisconnectPolicy, RoomCodeMode};
+use crate::errors::{MuuzikaError, MuuzikaResult};
+use crate::lobby;
+use crate::lobby::CreateOrJoinRoomRequest;
+use crate::rooms::{ClientMetadata, DisconnectPolicy};
+use crate::state::{State, WrappedRoom};
+use crate::tests::harness::{
+    eventually, fake_connection, request, test_config, test_state, TestClient, TestServer,
+};
+
+#[tokio::test]
+async fn create_then_connect_receives_sync() {
+    let server = TestServer::start().await;
+    let (room_code, token) = server.create_room("alice").await;
+
+    let mut alice = server.connect(&token).await;
+    let sync = alice.recv().await;
+
+    assert_eq!(sync["type"], "Sync");
+    assert_eq!(sync["data"]["you"], "alice");
+    assert_eq!(sync["data"]["isLeader"], true);
+    assert_eq!(sync["data"]["room"]["code"], room_code);
+    assert_eq!(sync["data"]["room"]["leader"], "alice");
+    assert!(server
+        .state
+        .rooms
+        .read()
+        .await
+        .contains_key(&room_code.parse().unwrap()));
+    assert_eq!(
+        sync["data"]["room"]["players"],
+        json!([{ "username": "alice", "displayName": "alice", "score": 0, "isOnline": true }])
+    );
+}
+
+#[tokio::test]
+async fn join_then_connect_notifies_room() {
+    let server = TestServer::start().await;
+    let (room_code, leader_token) = server.create_room("alice").await;
+    let mut alice = server.connect(&leader_token).await;
+    alice.recv_type("Sync").await;
+
+    let (joined_code, token) = server.join_room(&room_code, "bob").await;
+    assert_eq!(joined_code, room_code);
+    assert_eq!(
+        alice.recv().await,
+        json!({ "type": "PlayerJoined", "data": "bob" })
+    );
+
+    let mut bob = server.connect(&token).await;
+    let sync = bob.recv_type("Sync").await;
+    assert_eq!(sync["data"]["you"], "bob");
+    assert_eq!(sync["data"]["isLeader"], false);
+    assert_eq!(sync["data"]["room"]["players"].as_array().unwrap().len(), 2);
+
+    assert_eq!(
+        alice.recv().await,
+        json!({ "type": "PlayerConnected", "data": "bob" })
+    );
+}
+
+#[tokio::test]
+async fn dead_connection_is_disconnected_on_broadcast() {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+    let response = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+
+    let (conn, rx) = fake_connection();
+    let (wrapped_room, _) = lobby::connect_player(&state, &response.token, None, &conn, &metadata)
+        .await
+        .unwrap();
+    drop(rx);
+
+    lobby::join_room(&state, &response.room_code, &request("bob"), &metadata)
+        .await
+        .unwrap();
+
+    let alice = "alice".parse().unwrap();
+    eventually(|| async {
+        let room = wrapped_room.read().await;
+        room.get_player(&alice).unwrap().ws.is_none()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn ws_nonce_is_single_use_and_rotated() {
+    let state = State::with_config(Config {
+        require_ws_nonce: true,
+        ..test_config()
+    });
+    let metadata = ClientMetadata::default();
+    let response = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+    let nonce = response.nonce.unwrap();
+
+    let (conn, _rx) = fake_connection();
+    let (_, sync) = lobby::connect_player(&state, &response.token, Some(&nonce), &conn, &metadata)
+        .await
+        .unwrap();
+    let next_nonce = sync.next_nonce.unwrap();
+    assert_ne!(next_nonce, nonce);
+
+    let (conn, _rx) = fake_connection();
+    let replayed =
+        lobby::connect_player(&state, &response.token, Some(&nonce), &conn, &metadata).await;
+    assert!(matches!(replayed, Err(MuuzikaError::InvalidNonce)));
+
+    let (conn, _rx) = fake_connection();
+    let missing = lobby::connect_player(&state, &response.token, None, &conn, &metadata).await;
+    assert!(matches!(missing, Err(MuuzikaError::InvalidNonce)));
+
+    let (conn, _rx) = fake_connection();
+    lobby::connect_player(&state, &response.token, Some(&next_nonce), &conn, &metadata)
+        .await
+        .unwrap();
+}
+
+// Holds the lock of an empty room past its cleanup timer and lets a join line up
+// either before or after the cleanup that is waiting on the same lock
+async fn race_join_against_room_cleanup(join_first: bool) -> (State, MuuzikaResult<()>) {
+    let state = State::with_config(Config {
+        player_cleanup_delay: Duration::from_millis(10),
+        room_cleanup_delay: Duration::from_millis(100),
+        ..test_config()
+    });
+    let metadata = ClientMetadata::default();
+    let response = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+    let wrapped_room = state.rooms.read().await[&response.room_code].clone();
+
+    eventually(|| async { wrapped_room.read().await.cancel_cleanup.is_some() }).await;
+    let guard = wrapped_room.write().await;
+
+    let join = {
+        let state = state.clone();
+        let room_code = response.room_code.clone();
+        let metadata = metadata.clone();
+        async move {
+            lobby::join_room(&state, &room_code, &request("bob"), &metadata)
+                .await
+                .map(|_| ())
+        }
+    };
+
+    let join = if join_first {
+        let join = tokio::spawn(join);
+        sleep(Duration::from_millis(250)).await;
+        join
+    } else {
+        sleep(Duration::from_millis(200)).await;
+        let join = tokio::spawn(join);
+        sleep(Duration::from_millis(50)).await;
+        join
+    };
+
+    drop(guard);
+    (state, join.await.unwrap())
+}
+
+#[tokio::test]
+async fn join_racing_room_cleanup_is_rejected_as_retryable() {
+    let (state, joined) = race_join_against_room_cleanup(false).await;
+
+    assert!(matches!(joined, Err(MuuzikaError::RoomClosing { .. })));
+    assert!(state.rooms.read().await.is_empty());
+}
+
+#[tokio::test]
+async fn join_winning_the_race_keeps_the_room() {
+    let (state, joined) = race_join_against_room_cleanup(true).await;
+
+    // The join only returns after locking the room again to schedule bob's cleanup,
+    // and the cleanup queued before that, so it has already given up by now
+    assert!(joined.is_ok(), "{:?}", joined);
+    let rooms = state.rooms.read().await;
+    let room = rooms.values().next().unwrap().read().await;
+    assert!(!room.cleaning_up);
+    assert!(room.get_player(&"bob".parse().unwrap()).is_ok());
+}
+
+#[tokio::test]
+async fn broadcasts_during_a_brief_disconnect_are_delivered_on_reconnect() {
+    let server = TestServer::with_state(State::with_config(Config {
+        outbox_capacity: 2,
+        ..test_config()
+    }))
+    .await;
+    let (room_code, leader_token) = server.create_room("alice").await;
+    let mut alice = server.connect(&leader_token).await;
+    alice.recv_type("Sync").await;
+    let (_, token) = server.join_room(&room_code, "bob").await;
+    let mut bob = server.connect(&token).await;
+    bob.recv_type("Sync").await;
+
+    bob.ws.close(None).await.unwrap();
+    let wrapped_room = server.state.rooms.read().await[&room_code.parse().unwrap()].clone();
+    let bob_name = "bob".parse().unwrap();
+    eventually(|| async {
+        let room = wrapped_room.read().await;
+        room.get_player(&bob_name).unwrap().ws.is_none()
+    })
+    .await;
+
+    // One more than fits, so the oldest is dropped
+    server.join_room(&room_code, "carol").await;
+    server.join_room(&room_code, "dave").await;
+    server.join_room(&room_code, "erin").await;
+
+    let mut bob = server.connect(&token).await;
+    assert_eq!(
+        bob.recv().await,
+        json!({ "type": "PlayerJoined", "data": "dave" })
+    );
+    assert_eq!(
+        bob.recv().await,
+        json!({ "type": "PlayerJoined", "data": "erin" })
+    );
+    assert_eq!(bob.recv().await["type"], "Sync");
+}
+
+#[tokio::test]
+async fn first_connect_starts_with_the_sync() {
+    let server = TestServer::with_state(State::with_config(Config {
+        outbox_capacity: 2,
+        ..test_config()
+    }))
+    .await;
+    let (room_code, leader_token) = server.create_room("alice").await;
+    let (_, token) = server.join_room(&room_code, "bob").await;
+    server.join_room(&room_code, "carol").await;
+
+    let mut alice = server.connect(&leader_token).await;
+    assert_eq!(alice.recv().await["type"], "Sync");
+    let mut bob = server.connect(&token).await;
+    assert_eq!(bob.recv().await["type"], "Sync");
+}
+
+async fn disconnect_bob(disconnect_policy: DisconnectPolicy) -> WrappedRoom {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+    let response = lobby::create_room(
+        &state,
+        &CreateOrJoinRoomRequest {
+            disconnect_policy,
+            ..request("alice")
+        },
+        &metadata,
+    )
+    .await
+    .unwrap();
+    let joined = lobby::join_room(&state, &response.room_code, &request("bob"), &metadata)
+        .await
+        .unwrap();
+
+    let (conn, _rx) = fake_connection();
+    let (wrapped_room, _) = lobby::connect_player(&state, &joined.token, None, &conn, &metadata)
+        .await
+        .unwrap();
+    lobby::disconnect_player(&state, &wrapped_room, &"bob".parse().unwrap(), &conn)
+        .await
+        .unwrap();
+
+    wrapped_room
+}
+
+#[tokio::test]
+async fn disconnected_player_is_held_by_default() {
+    let wrapped_room = disconnect_bob(DisconnectPolicy::HoldForReconnect).await;
+
+    let room = wrapped_room.read().await;
+    let bob = room.get_player(&"bob".parse().unwrap()).unwrap();
+    assert!(bob.ws.is_none());
+}
+
+#[tokio::test]
+async fn disconnected_player_is_removed_right_away_when_configured() {
+    let wrapped_room = disconnect_bob(DisconnectPolicy::RemoveImmediately).await;
+
+    let room = wrapped_room.read().await;
+    assert!(room.get_player(&"bob".parse().unwrap()).is_err());
+    assert_eq!(room.players.len(), 1);
+}
+
+#[tokio::test]
+async fn practice_room_stays_out_of_the_code_pool() {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+    let available_codes = state.available_codes.remaining();
+
+    let response = lobby::create_room(
+        &state,
+        &CreateOrJoinRoomRequest {
+            practice: true,
+            ..request("alice")
+        },
+        &metadata,
+    )
+    .await
+    .unwrap();
+    assert!(response.room_code.is_practice());
+    assert_eq!(state.available_codes.remaining(), available_codes);
+
+    let joined = lobby::join_room(&state, &response.room_code, &request("bob"), &metadata).await;
+    assert!(matches!(joined, Err(MuuzikaError::PracticeRoom { .. })));
+
+    let (conn, _rx) = fake_connection();
+    let (wrapped_room, _) = lobby::connect_player(&state, &response.token, None, &conn, &metadata)
+        .await
+        .unwrap();
+    lobby::disconnect_player(&state, &wrapped_room, &"alice".parse().unwrap(), &conn)
+        .await
+        .unwrap();
+
+    assert!(state.rooms.read().await.is_empty());
+    assert_eq!(state.available_codes.remaining(), available_codes);
+}
+
+#[tokio::test]
+async fn taken_username_comes_with_suggestions() {
+    let server = TestServer::start().await;
+    let (room_code, _) = server.create_room("alice").await;
+
+    let (status, body) = server
+        .request(
+            Method::POST,
+            &format!("/rooms/{}", room_code),
+            Some(json!({ "username": "alice" })),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::CONFLICT);
+    let suggestions = body["data"]["suggestions"].as_array().unwrap();
+    assert_eq!(suggestions.len(), 3);
+    assert!(suggestions
+        .iter()
+        .all(|s| s.as_str().unwrap().starts_with("alice") && s != "alice"));
+}
+
+#[tokio::test]
+async fn rooms_per_host_are_capped_until_one_is_cleaned_up() {
+    let state = State::with_config(Config {
+        max_rooms_per_host: Some(2),
+        player_cleanup_delay: Duration::from_millis(10),
+        room_cleanup_delay: Duration::from_millis(300),
+        ..test_config()
+    });
+    let host = ClientMetadata {
+        ip: Some("203.0.113.7".parse().unwrap()),
+        ..ClientMetadata::default()
+    };
+    let other_host = ClientMetadata {
+        ip: Some("203.0.113.8".parse().unwrap()),
+        ..ClientMetadata::default()
+    };
+
+    let first = lobby::create_room(&state, &request("alice"), &host)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(150)).await;
+    lobby::create_room(&state, &request("bob"), &host)
+        .await
+        .unwrap();
+
+    let rejected = lobby::create_room(&state, &request("carol"), &host).await;
+    assert!(matches!(
+        rejected,
+        Err(MuuzikaError::TooManyRooms { limit: 2 })
+    ));
+    lobby::create_room(&state, &request("dave"), &other_host)
+        .await
+        .unwrap();
+
+    // Alice's room empties out first, freeing one slot
+    eventually(|| async { !state.rooms.read().await.contains_key(&first.room_code) }).await;
+    lobby::create_room(&state, &request("carol"), &host)
+        .await
+        .unwrap();
+    let rejected = lobby::create_room(&state, &request("erin"), &host).await;
+    assert!(matches!(rejected, Err(MuuzikaError::TooManyRooms { .. })));
+}
+
+// Alice leads with bob in the room, then drops her connection
+async fn leader_disconnects(
+    leader_disconnect_policy: LeaderDisconnectPolicy,
+) -> (TestServer, String, String, TestClient) {
+    let server = TestServer::with_state(State::with_config(Config {
+        player_cleanup_delay: Duration::from_millis(200),
+        leader_disconnect_policy,
+        ..test_config()
+    }))
+    .await;
+    let (room_code, leader_token) = server.create_room("alice").await;
+    let mut alice = server.connect(&leader_token).await;
+    alice.recv_type("Sync").await;
+    let (_, token) = server.join_room(&room_code, "bob").await;
+    let mut bob = server.connect(&token).await;
+    bob.recv_type("Sync").await;
+
+    alice.ws.close(None).await.unwrap();
+    (server, room_code, leader_token, bob)
+}
+
+#[tokio::test]
+async fn leader_returning_within_the_grace_period_keeps_the_room() {
+    let (server, room_code, leader_token, _bob) =
+        leader_disconnects(LeaderDisconnectPolicy::CloseRoom).await;
+
+    sleep(Duration::from_millis(50)).await;
+    let mut alice = server.connect(&leader_token).await;
+    let sync = alice.recv_type("Sync").await;
+    assert_eq!(sync["data"]["isLeader"], true);
+
+    sleep(Duration::from_millis(300)).await;
+    let rooms = server.state.rooms.read().await;
+    let room = rooms[&room_code.parse().unwrap()].read().await;
+    assert_eq!(room.leader, "alice".parse().unwrap());
+    assert_eq!(room.players.len(), 2);
+}
+
+#[tokio::test]
+async fn leader_gone_for_good_is_replaced() {
+    let (_server, _, _, mut bob) = leader_disconnects(LeaderDisconnectPolicy::Reelect).await;
+
+    let leader_changed = bob.recv_type("LeaderChanged").await;
+    assert_eq!(leader_changed["data"], "bob");
+}
+
+#[tokio::test]
+async fn leader_gone_for_good_closes_the_room_when_configured() {
+    let (server, room_code, _, mut bob) =
+        leader_disconnects(LeaderDisconnectPolicy::CloseRoom).await;
+
+    let error = bob.recv_type("Error").await;
+    assert_eq!(error["data"]["error"], "LeaderLeft");
+    assert_eq!(error["data"]["fatal"], true);
+    assert!(!server
+        .state
+        .rooms
+        .read()
+        .await
+        .contains_key(&room_code.parse().unwrap()));
+}
+
+#[tokio::test]
+async fn player_count_follows_joins_connects_and_disconnects() {
+    let server = TestServer::with_state(State::with_config(Config {
+        broadcast_player_count: true,
+        disconnect_broadcast_delay: Duration::from_millis(500),
+        ..test_config()
+    }))
+    .await;
+    let (room_code, leader_token) = server.create_room("alice").await;
+    let mut alice = server.connect(&leader_token).await;
+    alice.recv_type("Sync").await;
+
+    let (_, token) = server.join_room(&room_code, "bob").await;
+    assert_eq!(
+        alice.recv_type("PlayerCountChanged").await["data"],
+        json!({ "connected": 1, "total": 2 })
+    );
+
+    let mut bob = server.connect(&token).await;
+    assert_eq!(bob.recv().await["type"], "Sync");
+    assert_eq!(
+        alice.recv_type("PlayerCountChanged").await["data"],
+        json!({ "connected": 2, "total": 2 })
+    );
+
+    // A quick reconnect is never announced, so the count doesn't flicker
+    bob.ws.close(None).await.unwrap();
+    let wrapped_room = server.state.rooms.read().await[&room_code.parse().unwrap()].clone();
+    let bob_name = "bob".parse().unwrap();
+    eventually(|| async {
+        let room = wrapped_room.read().await;
+        room.get_player(&bob_name).unwrap().ws.is_none()
+    })
+    .await;
+    let mut bob = server.connect(&token).await;
+    assert_eq!(bob.recv().await["type"], "Sync");
+    let counts: Vec<Value> = drain(&mut alice, Duration::from_millis(700))
+        .await
+        .into_iter()
+        .filter(|message| message["type"] == "PlayerCountChanged")
+        .collect();
+    assert_eq!(counts, Vec::<Value>::new());
+
+    bob.ws.close(None).await.unwrap();
+    assert_eq!(
+        alice.recv_type("PlayerCountChanged").await["data"],
+        json!({ "connected": 1, "total": 2 })
+    );
+}
+
+#[tokio::test]
+async fn connecting_to_a_room_that_is_gone_is_room_not_found() {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+    let response = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+    state.rooms.write().await.remove(&response.room_code);
+
+    let (conn, _rx) = fake_connection();
+    let connected = lobby::connect_player(&state, &response.token, None, &conn, &metadata).await;
+    assert!(matches!(connected, Err(MuuzikaError::RoomNotFound { .. })));
+}
+
+#[tokio::test]
+async fn connecting_after_being_evicted_is_player_not_in_room() {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+    let response = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+    let joined = lobby::join_room(&state, &response.room_code, &request("bob"), &metadata)
+        .await
+        .unwrap();
+    let wrapped_room = state.rooms.read().await[&response.room_code].clone();
+    let bob = "bob".parse().unwrap();
+
+    wrapped_room.write().await.remove_player(&bob);
+    let (conn, _rx) = fake_connection();
+    let connected = lobby::connect_player(&state, &joined.token, None, &conn, &metadata).await;
+    assert!(matches!(
+        connected,
+        Err(MuuzikaError::PlayerNotInRoom { .. })
+    ));
+
+    // Someone else took the name since, the old token still doesn't get in. The
+    // tokens differ by creation time, which has millisecond resolution
+    sleep(Duration::from_millis(5)).await;
+    lobby::join_room(&state, &response.room_code, &request("bob"), &metadata)
+        .await
+        .unwrap();
+    let connected = lobby::connect_player(&state, &joined.token, None, &conn, &metadata).await;
+    assert!(matches!(
+        connected,
+        Err(MuuzikaError::PlayerNotInRoom { .. })
+    ));
+}
+
+#[tokio::test]
+async fn unknown_room_and_taken_name_answer_with_the_same_shape() {
+    let server = TestServer::start().await;
+    let (room_code, _) = server.create_room("alice").await;
+
+    let (not_found_status, not_found) = server
+        .request(
+            Method::POST,
+            "/rooms/nope",
+            Some(json!({ "username": "alice" })),
+        )
+        .await;
+    let (taken_status, taken) = server
+        .request(
+            Method::POST,
+            &format!("/rooms/{}", room_code),
+            Some(json!({ "username": "alice" })),
+        )
+        .await;
+
+    assert_eq!(not_found_status, StatusCode::NOT_FOUND);
+    assert_eq!(taken_status, StatusCode::CONFLICT);
+    let keys = |body: &Value| {
+        body.as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(keys(&not_found), keys(&taken));
+    assert_eq!(not_found["data"]["roomCode"], "nope");
+    assert_eq!(taken["data"]["roomCode"], room_code);
+}
+
+// Everything the client gets until nothing arrives for `quiet`
+async fn drain(client: &mut TestClient, quiet: Duration) -> Vec<Value> {
+    let mut messages = Vec::new();
+    while let Ok(Some(Ok(frame))) = timeout(quiet, client.ws.next()).await {
+        if let Ok(text) = frame.to_text() {
+            messages.push(serde_json::from_str(text).unwrap());
+        }
+    }
+    messages
+}
+
+#[tokio::test]
+async fn quick_reconnect_announces_at_most_one_disconnect_and_connect() {
+    // Around the broadcast timer firing, so the reconnect races the broadcast
+    for delay_ms in [0, 50, 100, 150, 500] {
+        let server = TestServer::with_state(State::with_config(Config {
+            disconnect_broadcast_delay: Duration::from_millis(delay_ms),
+            ..test_config()
+        }))
+        .await;
+        let (room_code, leader_token) = server.create_room("alice").await;
+        let mut alice = server.connect(&leader_token).await;
+        alice.recv_type("Sync").await;
+        let (_, token) = server.join_room(&room_code, "bob").await;
+        let mut bob = server.connect(&token).await;
+        bob.recv_type("Sync").await;
+        drain(&mut alice, Duration::from_millis(50)).await;
+
+        bob.ws.close(None).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        let mut bob = server.connect(&token).await;
+        bob.recv_type("Sync").await;
+
+        let announced: Vec<_> = drain(&mut alice, Duration::from_millis(700))
+            .await
+            .into_iter()
+            .map(|message| message["type"].as_str().unwrap().to_string())
+            .filter(|kind| kind == "PlayerDisconnected" || kind == "PlayerConnected")
+            .collect();
+        let count = |kind: &str| announced.iter().filter(|k| *k == kind).count();
+        assert!(
+            count("PlayerDisconnected") <= 1,
+            "{}ms: {:?}",
+            delay_ms,
+            announced
+        );
+        assert!(
+            count("PlayerConnected") <= 1,
+            "{}ms: {:?}",
+            delay_ms,
+            announced
+        );
+        assert_ne!(
+            announced.last().map(String::as_str),
+            Some("PlayerDisconnected"),
+            "{}ms: bob is online but was last announced offline",
+            delay_ms
+        );
+    }
+}
+
+#[tokio::test]
+async fn same_idempotency_key_gets_the_same_room() {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+    let key = || Some("key-1".to_string());
+
+    let first = lobby::create_room_idempotent(&state, key(), &request("alice"), &metadata)
+        .await
+        .unwrap();
+    let retried = lobby::create_room_idempotent(&state, key(), &request("alice"), &metadata)
+        .await
+        .unwrap();
+
+    assert_eq!(retried.room_code, first.room_code);
+    assert_eq!(retried.token, first.token);
+    assert_eq!(state.rooms.read().await.len(), 1);
+}
+
+#[tokio::test]
+async fn different_idempotency_keys_get_distinct_rooms() {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+
+    let first = lobby::create_room_idempotent(
+        &state,
+        Some("key-1".to_string()),
+        &request("alice"),
+        &metadata,
+    )
+    .await
+    .unwrap();
+    let second = lobby::create_room_idempotent(
+        &state,
+        Some("key-2".to_string()),
+        &request("alice"),
+        &metadata,
+    )
+    .await
+    .unwrap();
+
+    assert_ne!(second.room_code, first.room_code);
+    assert_eq!(state.rooms.read().await.len(), 2);
+}
+
+#[tokio::test]
+async fn idempotency_key_is_tied_to_its_request() {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+    let key = || Some("key-1".to_string());
+
+    lobby::create_room_idempotent(&state, key(), &request("alice"), &metadata)
+        .await
+        .unwrap();
+    let reused = lobby::create_room_idempotent(&state, key(), &request("mallory"), &metadata).await;
+
+    assert!(matches!(
+        reused,
+        Err(MuuzikaError::IdempotencyKeyReused { .. })
+    ));
+    assert_eq!(state.rooms.read().await.len(), 1);
+}
+
+#[tokio::test]
+async fn idempotency_key_of_a_closed_room_creates_a_new_one() {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+    let key = || Some("key-1".to_string());
+
+    let first = lobby::create_room_idempotent(&state, key(), &request("alice"), &metadata)
+        .await
+        .unwrap();
+    state.rooms.write().await.remove(&first.room_code);
+
+    let retried = lobby::create_room_idempotent(&state, key(), &request("alice"), &metadata)
+        .await
+        .unwrap();
+    assert_ne!(retried.token, first.token);
+    assert!(state.rooms.read().await.contains_key(&retried.room_code));
+}
+
+async fn create_listed_room(server: &TestServer, username: &str, public: bool) -> String {
+    let (status, body) = server
+        .request(
+            Method::POST,
+            "/rooms",
+            Some(json!({ "username": username, "public": public })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::CREATED);
+    body["roomCode"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn only_public_rooms_are_listed_and_without_usernames() {
+    let server = TestServer::start().await;
+    let public = create_listed_room(&server, "alice", true).await;
+    create_listed_room(&server, "bob", false).await;
+
+    let (status, body) = server.request(Method::GET, "/rooms", None).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        json!({ "rooms": [{ "code": public, "playerCount": 1 }], "total": 1 })
+    );
+    assert!(!body.to_string().contains("alice"));
+}
+
+#[tokio::test]
+async fn public_rooms_are_paginated() {
+    let server = TestServer::with_state(State::with_config(Config {
+        room_code_mode: RoomCodeMode::Sequential,
+        ..test_config()
+    }))
+    .await;
+    for username in ["alice", "bob", "carol"] {
+        create_listed_room(&server, username, true).await;
+    }
+
+    let (_, body) = server
+        .request(Method::GET, "/rooms?offset=1&limit=1", None)
+        .await;
+    assert_eq!(body["total"], 3);
+    assert_eq!(body["rooms"], json!([{ "code": "0001", "playerCount": 1 }]));
+
+    let (_, body) = server.request(Method::GET, "/rooms?offset=3", None).await;
+    assert_eq!(body["total"], 3);
+    assert_eq!(body["rooms"], json!([]));
+}
+
+#[tokio::test]
+async fn idempotency_key_is_evicted_after_its_ttl() {
+    let state = State::with_config(Config {
+        idempotency_key_ttl: Duration::from_millis(20),
+        ..test_config()
+    });
+    let metadata = ClientMetadata::default();
+    let key = || Some("key-1".to_string());
+
+    let first = lobby::create_room_idempotent(&state, key(), &request("alice"), &metadata)
+        .await
+        .unwrap();
+    assert!(state.idempotency_keys.read().await.contains_key("key-1"));
+
+    sleep(Duration::from_millis(100)).await;
+    assert!(state.idempotency_keys.read().await.is_empty());
+
+    let retried = lobby::create_room_idempotent(&state, key(), &request("alice"), &metadata)
+        .await
+        .unwrap();
+    assert_ne!(retried.room_code, first.room_code);
+}
+
+#[tokio::test]
+async fn disconnecting_the_same_connection_twice_only_disconnects_once() {
+    let state = State::with_config(Config {
+        broadcast_player_count: true,
+        disconnect_broadcast_delay: Duration::from_millis(20),
+        player_cleanup_delay: Duration::from_millis(400),
+        ..test_config()
+    });
+    let metadata = ClientMetadata::default();
+    let created = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+    let joined = lobby::join_room(&state, &created.room_code, &request("bob"), &metadata)
+        .await
+        .unwrap();
+    let (alice_conn, mut alice_rx) = fake_connection();
+    let (wrapped_room, _) =
+        lobby::connect_player(&state, &created.token, None, &alice_conn, &metadata)
+            .await
+            .unwrap();
+    let (bob_conn, _bob_rx) = fake_connection();
+    lobby::connect_player(&state, &joined.token, None, &bob_conn, &metadata)
+        .await
+        .unwrap();
+    let bob = "bob".parse().unwrap();
+    while alice_rx.try_recv().is_ok() {}
+
+    lobby::disconnect_player(&state, &wrapped_room, &bob, &bob_conn)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(200)).await;
+    lobby::disconnect_player(&state, &wrapped_room, &bob, &bob_conn)
+        .await
+        .unwrap();
+
+    let mut counts = Vec::new();
+    while let Ok(message) = alice_rx.try_recv() {
+        let message: Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        if message["type"] == "PlayerCountChanged" {
+            counts.push(message["data"].clone());
+        }
+    }
+    assert_eq!(counts, [json!({ "connected": 1, "total": 2 })]);
+
+    // A second cleanup scheduled by the second disconnect would only run at 600ms
+    sleep(Duration::from_millis(300)).await;
+    assert!(wrapped_room.read().await.get_player(&bob).is_err());
+}
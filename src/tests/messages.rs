@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::time::sleep;
+
+use crate::messages::ClientMessage;
+use crate::tests::harness::TestServer;
+
+#[tokio::test]
+async fn add_is_broadcast_and_acked() {
+    let server = TestServer::start().await;
+    let (_, token) = server.create_room("alice").await;
+    let mut alice = server.connect(&token).await;
+    alice.recv_type("Sync").await;
+
+    alice
+        .send(json!({ "type": "Add", "data": [1, 2, 3], "ack": "a1" }))
+        .await;
+
+    assert_eq!(
+        alice.recv().await,
+        json!({ "type": "AddResult", "data": { "result": 6, "username": "alice" } })
+    );
+    assert_eq!(alice.recv().await, json!({ "type": "Noop", "ack": "a1" }));
+}
+
+#[tokio::test]
+async fn whoami_describes_the_connection() {
+    let server = TestServer::start().await;
+    let (room_code, leader_token) = server.create_room("alice").await;
+    let (_, token) = server.join_room(&room_code, "bob").await;
+
+    let mut alice = server.connect(&leader_token).await;
+    alice.recv_type("Sync").await;
+    let mut bob = server.connect(&token).await;
+    bob.recv_type("Sync").await;
+
+    bob.send(json!({ "type": "Whoami", "ack": "w" })).await;
+    let whoami = bob.recv_type("Whoami").await;
+
+    assert_eq!(whoami["ack"], "w");
+    assert_eq!(whoami["data"]["username"], "bob");
+    assert_eq!(whoami["data"]["roomCode"], room_code);
+    assert_eq!(whoami["data"]["isLeader"], false);
+
+    let room_code = room_code.parse().unwrap();
+    let rooms = server.state.rooms.read().await;
+    let room = rooms.get(&room_code).unwrap().read().await;
+    let conn = room.get_player(&"bob".parse().unwrap()).unwrap().ws.clone();
+    assert_eq!(whoami["data"]["connectionId"], conn.unwrap().id.to_string());
+}
+
+#[tokio::test]
+async fn heartbeat_bumps_activity_without_a_broadcast() {
+    let server = TestServer::start().await;
+    let (room_code, token) = server.create_room("alice").await;
+    let mut alice = server.connect(&token).await;
+    alice.recv_type("Sync").await;
+
+    let room_code = room_code.parse().unwrap();
+    let alice_name = "alice".parse().unwrap();
+    let last_active_at = || async {
+        let rooms = server.state.rooms.read().await;
+        let room = rooms.get(&room_code).unwrap().read().await;
+        room.get_player(&alice_name).unwrap().last_active_at()
+    };
+
+    let before = last_active_at().await;
+    sleep(Duration::from_millis(5)).await;
+
+    alice
+        .send(json!({ "type": "Heartbeat", "ack": "hb" }))
+        .await;
+    // The heartbeat's own answer is the first and only thing received
+    assert_eq!(alice.recv().await, json!({ "type": "Noop", "ack": "hb" }));
+    assert!(last_active_at().await > before);
+}
+
+#[tokio::test]
+async fn acks_are_echoed_as_strings() {
+    let server = TestServer::start().await;
+    let (_, token) = server.create_room("alice").await;
+    let mut alice = server.connect(&token).await;
+    alice.recv_type("Sync").await;
+
+    alice.send(json!({ "type": "Heartbeat", "ack": "a" })).await;
+    assert_eq!(alice.recv().await, json!({ "type": "Noop", "ack": "a" }));
+
+    alice.send(json!({ "type": "Heartbeat", "ack": 123 })).await;
+    assert_eq!(alice.recv().await, json!({ "type": "Noop", "ack": "123" }));
+
+    alice.send(json!({ "type": "Heartbeat" })).await;
+    assert_eq!(alice.recv().await, json!({ "type": "Noop" }));
+}
+
+#[test]
+fn unknown_fields_are_ignored() {
+    let add: ClientMessage = serde_json::from_value(json!({
+        "type": "Add",
+        "data": [1, 2],
+        "ack": "a",
+        "clientVersion": "9.9.9"
+    }))
+    .unwrap();
+    assert!(matches!(add, ClientMessage::Add(numbers) if numbers == vec![1, 2]));
+
+    let heartbeat: ClientMessage =
+        serde_json::from_value(json!({ "type": "Heartbeat", "sentAt": 1700000000000u64 })).unwrap();
+    assert!(matches!(heartbeat, ClientMessage::Heartbeat));
+}
+
+#[tokio::test]
+async fn avatar_is_broadcast_and_included_in_the_sync() {
+    let server = TestServer::start().await;
+    let (room_code, leader_token) = server.create_room("alice").await;
+    let mut alice = server.connect(&leader_token).await;
+    alice.recv_type("Sync").await;
+
+    alice
+        .send(json!({ "type": "SetAvatar", "data": { "avatar": "🦊" }, "ack": "s" }))
+        .await;
+    assert_eq!(
+        alice.recv().await,
+        json!({ "type": "PlayerAvatarChanged", "data": { "username": "alice", "avatar": "🦊" } })
+    );
+    assert_eq!(alice.recv().await, json!({ "type": "Noop", "ack": "s" }));
+
+    let (_, token) = server.join_room(&room_code, "bob").await;
+    let mut bob = server.connect(&token).await;
+    let sync = bob.recv_type("Sync").await;
+    let players = sync["data"]["room"]["players"].as_array().unwrap();
+    let alice = players.iter().find(|p| p["username"] == "alice").unwrap();
+    assert_eq!(alice["avatar"], "🦊");
+}
+
+#[tokio::test]
+async fn avatar_outside_the_allow_list_is_rejected() {
+    let server = TestServer::start().await;
+    let (room_code, token) = server.create_room("alice").await;
+    let mut alice = server.connect(&token).await;
+    alice.recv_type("Sync").await;
+
+    alice
+        .send(json!({ "type": "SetAvatar", "data": { "avatar": "<img>" }, "ack": "s" }))
+        .await;
+    let error = alice.recv_type("Error").await;
+    assert_eq!(error["ack"], "s");
+
+    let room_code = room_code.parse().unwrap();
+    let rooms = server.state.rooms.read().await;
+    let room = rooms.get(&room_code).unwrap().read().await;
+    assert!(room
+        .get_player(&"alice".parse().unwrap())
+        .unwrap()
+        .avatar
+        .is_none());
+}
@@ -0,0 +1,13 @@
+mod admin;
+mod analytics;
+mod auth;
+mod filters;
+mod harness;
+mod helpers;
+mod lobby;
+mod messages;
+mod rooms;
+mod serialization;
+mod state;
+mod timers;
+mod ws;
@@ -0,0 +1,269 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use serde::{Serialize, Serializer};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::UnboundedReceiver;
+use warp::ws::Message;
+
+use crate::messages::ServerMessage;
+use crate::rooms::{BroadcastTarget, ClientMetadata, Player, Room, RoomDto, Username};
+use crate::tests::harness::fake_connection;
+use crate::ws;
+
+fn username(name: &str) -> Username {
+    name.parse().unwrap()
+}
+
+fn room_with_players(names: &[&str]) -> Room {
+    let (tx, _) = mpsc::unbounded_channel();
+    let mut players = names.iter().enumerate().map(|(i, name)| {
+        let mut player = Player::new(username(name), ClientMetadata::default());
+        player.created_at = i as u64;
+        (username(name), player)
+    });
+
+    let (_, leader) = players.next().unwrap();
+    let mut room = Room::new("0000".parse().unwrap(), leader, false, tx, 0);
+    room.players.extend(players);
+    room.assert_invariants();
+    room
+}
+
+#[test]
+fn non_leader_leaving_keeps_leader() {
+    let mut room = room_with_players(&["alice", "bob", "carol"]);
+
+    room.remove_player(&username("bob")).unwrap();
+
+    room.assert_invariants();
+    assert_eq!(room.leader, username("alice"));
+}
+
+#[test]
+fn leader_leaving_hands_over_to_oldest_player() {
+    let mut room = room_with_players(&["alice", "bob", "carol"]);
+
+    room.remove_player(&username("alice")).unwrap();
+
+    room.assert_invariants();
+    assert_eq!(room.leader, username("bob"));
+}
+
+#[test]
+fn everyone_leaving_leaves_an_empty_room() {
+    let mut room = room_with_players(&["alice", "bob"]);
+
+    room.remove_player(&username("alice")).unwrap();
+    room.assert_invariants();
+    room.remove_player(&username("bob")).unwrap();
+
+    room.assert_invariants();
+    assert!(room.players.is_empty());
+}
+
+#[test]
+fn long_names_are_only_truncated_for_display() {
+    let long_name = "bartholomew_the_magnificent";
+    let long_name = &long_name[..Username::MAX_LENGTH];
+    let mut room = room_with_players(&["alice", long_name]);
+    room.display_length = 10;
+
+    let players = RoomDto::from(&room).players;
+    let player = players
+        .iter()
+        .find(|p| p.username == username(long_name))
+        .unwrap();
+
+    assert_eq!(player.display_name, "bartholom…");
+    assert!(room.get_player(&username(long_name)).is_ok());
+    let alice = players.iter().find(|p| p.username == username("alice"));
+    assert_eq!(alice.unwrap().display_name, "alice");
+}
+
+#[test]
+fn username_suggestions_are_free_and_valid() {
+    let members: Vec<String> = (1000..10000)
+        .step_by(2)
+        .map(|n| format!("alice{}", n))
+        .collect();
+    let members: Vec<&str> = members.iter().map(String::as_str).collect();
+    let room = room_with_players(&members);
+
+    for _ in 0..20 {
+        let suggestions = room.suggest_usernames(&username("alice"));
+
+        assert!(!suggestions.is_empty());
+        for suggestion in &suggestions {
+            assert!(room.get_player(suggestion).is_err());
+            assert_eq!(
+                &Username::try_new(suggestion.to_string()).unwrap(),
+                suggestion
+            );
+        }
+    }
+}
+
+#[test]
+fn username_suggestions_are_random_suffixes() {
+    let room = room_with_players(&["alice", "alice2", "alice3"]);
+
+    let suggestions = room.suggest_usernames(&username("alice"));
+
+    assert_eq!(suggestions.len(), 3);
+    for suggestion in &suggestions {
+        let suffix = suggestion
+            .to_string()
+            .strip_prefix("alice")
+            .unwrap()
+            .to_string();
+        assert_eq!(suffix.len(), 4);
+        assert!(suffix.chars().all(|c| c.is_ascii_digit()));
+    }
+    let distinct: HashSet<&Username> = suggestions.iter().collect();
+    assert_eq!(distinct.len(), 3);
+}
+
+#[test]
+fn username_suggestions_fit_the_max_length() {
+    let long_name = "a".repeat(Username::MAX_LENGTH);
+    let room = room_with_players(&[&long_name]);
+
+    let suggestions = room.suggest_usernames(&username(&long_name));
+
+    assert_eq!(suggestions.len(), 3);
+    for suggestion in suggestions {
+        assert_ne!(suggestion, username(&long_name));
+        assert_eq!(
+            Username::try_new(suggestion.to_string()).unwrap(),
+            suggestion
+        );
+    }
+}
+
+fn connect_all(room: &mut Room) -> Vec<(Username, UnboundedReceiver<Message>)> {
+    room.players
+        .iter_mut()
+        .map(|(username, player)| {
+            let (conn, rx) = fake_connection();
+            player.ws = Some(conn);
+            (username.clone(), rx)
+        })
+        .collect()
+}
+
+fn received(
+    receivers: &mut [(Username, UnboundedReceiver<Message>)],
+) -> Vec<(String, Vec<Message>)> {
+    let mut received: Vec<_> = receivers
+        .iter_mut()
+        .map(|(username, rx)| {
+            let mut messages = Vec::new();
+            while let Ok(message) = rx.try_recv() {
+                messages.push(message);
+            }
+            (username.to_string(), messages)
+        })
+        .collect();
+    received.sort_by(|a, b| a.0.cmp(&b.0));
+    received
+}
+
+#[test]
+fn broadcast_targets_reach_exactly_the_intended_players() {
+    let mut room = room_with_players(&["alice", "bob", "carol"]);
+    let mut receivers = connect_all(&mut room);
+
+    room.send_to("all", BroadcastTarget::All).unwrap();
+    let counts: Vec<_> = received(&mut receivers)
+        .into_iter()
+        .map(|(name, messages)| (name, messages.len()))
+        .collect();
+    assert_eq!(
+        counts,
+        [
+            ("alice".to_string(), 1),
+            ("bob".to_string(), 1),
+            ("carol".to_string(), 1)
+        ]
+    );
+
+    room.send_to("not bob", BroadcastTarget::Except(&username("bob")))
+        .unwrap();
+    let counts: Vec<_> = received(&mut receivers)
+        .into_iter()
+        .map(|(name, messages)| (name, messages.len()))
+        .collect();
+    assert_eq!(
+        counts,
+        [
+            ("alice".to_string(), 1),
+            ("bob".to_string(), 0),
+            ("carol".to_string(), 1)
+        ]
+    );
+}
+
+static SERIALIZATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingMessage;
+
+impl Serialize for CountingMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SERIALIZATIONS.fetch_add(1, Ordering::SeqCst);
+        serializer.serialize_str("counted")
+    }
+}
+
+#[test]
+fn broadcast_is_serialized_once_for_every_recipient() {
+    let mut room = room_with_players(&["alice", "bob", "carol", "dave"]);
+    let mut receivers = connect_all(&mut room);
+
+    room.send(CountingMessage).unwrap();
+
+    assert_eq!(SERIALIZATIONS.load(Ordering::SeqCst), 1);
+    let received = received(&mut receivers);
+    let first = &received[0].1;
+    assert_eq!(first.len(), 1);
+    assert!(received.iter().all(|(_, messages)| messages == first));
+}
+
+// Not a correctness test, run with `cargo test -- --ignored --nocapture` to compare
+// a batched broadcast against serializing the same message once per recipient
+#[test]
+#[ignore]
+fn batched_broadcast_against_per_send_for_100_players() {
+    const ROUNDS: u32 = 1000;
+
+    let names: Vec<String> = (0..100).map(|n| format!("player{}", n)).collect();
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let mut room = room_with_players(&names);
+    let mut receivers = connect_all(&mut room);
+    let message = || ServerMessage::Announcement {
+        text: "x".repeat(256),
+    };
+
+    let started = Instant::now();
+    for _ in 0..ROUNDS {
+        room.send(message()).unwrap();
+        received(&mut receivers);
+    }
+    let batched = started.elapsed();
+
+    let started = Instant::now();
+    for _ in 0..ROUNDS {
+        for player in room.players.values() {
+            let frame = ws::make_message(message(), None).unwrap();
+            player.ws.as_ref().unwrap().send_raw(frame);
+        }
+        received(&mut receivers);
+    }
+    let per_send = started.elapsed();
+
+    println!(
+        "100 players, {} rounds: batched {:?}, per send {:?}",
+        ROUNDS, batched, per_send
+    );
+}
@@ -0,0 +1,34 @@
+use chrono::{TimeZone, Utc};
+use serde::{Serialize, Serializer};
+use serde_json::json;
+
+use crate::serialization::{serialize_utc_date_time_as, TimestampFormat};
+
+struct Timestamp(chrono::DateTime<Utc>, TimestampFormat);
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_utc_date_time_as(self.1, &self.0, serializer)
+    }
+}
+
+#[test]
+fn same_instant_in_both_formats() {
+    let instant = Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+
+    assert_eq!(
+        serde_json::to_value(Timestamp(instant, TimestampFormat::Rfc3339)).unwrap(),
+        json!("2023-11-14T22:13:20.123+00:00")
+    );
+    assert_eq!(
+        serde_json::to_value(Timestamp(instant, TimestampFormat::EpochMillis)).unwrap(),
+        json!(1_700_000_000_123i64)
+    );
+}
+
+#[test]
+fn timestamp_format_is_parsed_from_env_values() {
+    assert_eq!("rfc3339".parse(), Ok(TimestampFormat::Rfc3339));
+    assert_eq!("millis".parse(), Ok(TimestampFormat::EpochMillis));
+    assert!("unix".parse::<TimestampFormat>().is_err());
+}
@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::config::{Config, RoomCodeMode};
+use crate::errors::MuuzikaError;
+use crate::lobby;
+use crate::rooms::ClientMetadata;
+use crate::state::State;
+use crate::tests::harness::{eventually, request, test_config};
+
+#[tokio::test]
+async fn state_is_built_from_an_explicit_config() {
+    let state = State::with_config(Config {
+        room_code_length: 1,
+        player_cleanup_delay: Duration::from_millis(10),
+        room_cleanup_delay: Duration::from_millis(10),
+        ..test_config()
+    });
+    assert_eq!(state.available_codes.remaining(), 10);
+
+    let response = lobby::create_room(&state, &request("alice"), &ClientMetadata::default())
+        .await
+        .unwrap();
+    assert_eq!(response.room_code.to_string().len(), 1);
+    assert_eq!(state.available_codes.remaining(), 9);
+
+    // Nobody ever connects, so the short delays clean the room up right away
+    eventually(|| async { state.rooms.read().await.is_empty() }).await;
+    eventually(|| async { state.available_codes.remaining() == 10 }).await;
+}
+
+#[tokio::test]
+async fn sequential_mode_hands_out_codes_in_order() {
+    let state = State::with_config(Config {
+        room_code_mode: RoomCodeMode::Sequential,
+        ..test_config()
+    });
+    let metadata = ClientMetadata::default();
+
+    for expected in ["0000", "0001", "0002"] {
+        let response = lobby::create_room(&state, &request("alice"), &metadata)
+            .await
+            .unwrap();
+        assert_eq!(response.room_code.to_string(), expected);
+    }
+}
+
+#[tokio::test]
+async fn failed_create_leaks_neither_a_room_nor_a_code() {
+    let state = State::with_config(Config {
+        room_code_mode: RoomCodeMode::Sequential,
+        ..test_config()
+    });
+    let metadata = ClientMetadata::default();
+    let existing = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+
+    // A pool that hands out a code that's still in use makes the create fail after the pop
+    let available_codes = state.available_codes.remaining();
+    state.available_codes.push(existing.room_code.clone());
+
+    let created = lobby::create_room(&state, &request("bob"), &metadata).await;
+    assert!(matches!(created, Err(MuuzikaError::RoomCodeInUse { .. })));
+
+    let rooms = state.rooms.read().await;
+    assert_eq!(rooms.len(), 1);
+    let room = rooms[&existing.room_code].read().await;
+    assert_eq!(room.leader, "alice".parse().unwrap());
+    assert_eq!(state.available_codes.remaining(), available_codes);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_creates_never_share_a_code() {
+    let state = State::with_config(Config {
+        room_code_length: 3,
+        ..test_config()
+    });
+
+    let creates: Vec<_> = (0..1100)
+        .map(|_| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                lobby::create_room(&state, &request("alice"), &ClientMetadata::default()).await
+            })
+        })
+        .collect();
+
+    let mut codes = HashSet::new();
+    let mut out_of_codes = 0;
+    for create in creates {
+        match create.await.unwrap() {
+            Ok(response) => assert!(codes.insert(response.room_code), "code issued twice"),
+            Err(MuuzikaError::OutOfRoomCodes) => out_of_codes += 1,
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    assert_eq!(codes.len(), 1000);
+    assert_eq!(out_of_codes, 100);
+    assert_eq!(state.rooms.read().await.len(), 1000);
+    assert_eq!(state.available_codes.remaining(), 0);
+}
@@ -0,0 +1,64 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use tokio::sync::oneshot;
+use tokio::time::sleep;
+
+use crate::tests::harness::eventually;
+use crate::timers::Timers;
+
+#[tokio::test]
+async fn many_deadlines_fire_in_order() {
+    let timers = Timers::new(1);
+    let fired = Arc::new(Mutex::new(Vec::new()));
+    let mut cancels = Vec::new();
+
+    // Spaced out so the time spent scheduling can't reorder neighbours
+    let mut delays: Vec<u64> = (0..100).map(|i| i * 3).collect();
+    delays.shuffle(&mut thread_rng());
+    for delay in delays {
+        let (tx, rx) = oneshot::channel();
+        cancels.push(tx);
+        let fired = fired.clone();
+        timers.schedule(Duration::from_millis(delay), rx, move || {
+            fired.lock().unwrap().push(delay);
+        });
+    }
+
+    eventually(|| async { fired.lock().unwrap().len() == 100 }).await;
+    let fired = fired.lock().unwrap().clone();
+    assert_eq!(fired, (0..100).map(|i| i * 3).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn sent_or_dropped_cancel_keeps_the_timer_from_firing() {
+    let timers = Timers::new(2);
+    let fired = Arc::new(Mutex::new(Vec::new()));
+
+    let (sent, rx) = oneshot::channel();
+    let on_fire = fired.clone();
+    timers.schedule(Duration::from_millis(10), rx, move || {
+        on_fire.lock().unwrap().push("sent")
+    });
+
+    let (dropped, rx) = oneshot::channel::<()>();
+    let on_fire = fired.clone();
+    timers.schedule(Duration::from_millis(10), rx, move || {
+        on_fire.lock().unwrap().push("dropped")
+    });
+
+    let (kept, rx) = oneshot::channel::<()>();
+    let on_fire = fired.clone();
+    timers.schedule(Duration::from_millis(10), rx, move || {
+        on_fire.lock().unwrap().push("kept")
+    });
+
+    let _ = sent.send(());
+    drop(dropped);
+
+    eventually(|| async { kept.is_closed() }).await;
+    sleep(Duration::from_millis(20)).await;
+    assert_eq!(*fired.lock().unwrap(), vec!["kept"]);
+}
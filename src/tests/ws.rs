@@ -0,0 +1,255 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::{Sink, Stream};
+use serde_json::json;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message as TungsteniteMessage;
+use warp::ws::Message;
+
+use crate::lobby;
+use crate::rooms::ClientMetadata;
+use crate::tests::harness::{eventually, request, test_state, TestServer};
+use crate::ws::{handle_ws_upgrade, ConnectionId, WsQuery};
+
+// A socket whose peer is gone without the read side ever noticing
+struct HalfDeadSocket;
+
+impl Stream for HalfDeadSocket {
+    type Item = Result<Message, io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Pending
+    }
+}
+
+impl Sink<Message> for HalfDeadSocket {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, _item: Message) -> Result<(), Self::Error> {
+        Err(io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn failed_write_disconnects_without_a_read_event() {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+    let response = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+
+    let query = WsQuery {
+        token: response.token,
+        nonce: None,
+    };
+    // Sending the sync fails, which has to end the connection on its own
+    timeout(
+        Duration::from_secs(1),
+        handle_ws_upgrade(HalfDeadSocket, state.clone(), query, metadata),
+    )
+    .await
+    .expect("connection was not dropped after the write failed");
+
+    let rooms = state.rooms.read().await;
+    let room = rooms[&response.room_code].read().await;
+    assert!(room
+        .get_player(&"alice".parse().unwrap())
+        .unwrap()
+        .ws
+        .is_none());
+}
+
+// A socket that takes every write and never has anything to read
+struct IdleSocket;
+
+impl Stream for IdleSocket {
+    type Item = Result<Message, io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Pending
+    }
+}
+
+impl Sink<Message> for IdleSocket {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, _item: Message) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn cancelling_the_connection_ends_the_loop() {
+    let state = test_state();
+    let metadata = ClientMetadata::default();
+    let response = lobby::create_room(&state, &request("alice"), &metadata)
+        .await
+        .unwrap();
+    let wrapped_room = state.rooms.read().await[&response.room_code].clone();
+    let alice = "alice".parse().unwrap();
+
+    let query = WsQuery {
+        token: response.token,
+        nonce: None,
+    };
+    let handle = tokio::spawn(handle_ws_upgrade(
+        IdleSocket,
+        state.clone(),
+        query,
+        metadata,
+    ));
+
+    eventually(|| async {
+        let room = wrapped_room.read().await;
+        room.get_player(&alice).unwrap().ws.is_some()
+    })
+    .await;
+    let conn = wrapped_room
+        .read()
+        .await
+        .get_player(&alice)
+        .unwrap()
+        .ws
+        .clone()
+        .unwrap();
+    conn.cancel();
+
+    timeout(Duration::from_secs(1), handle)
+        .await
+        .expect("connection loop did not end after being cancelled")
+        .unwrap();
+    let room = wrapped_room.read().await;
+    assert!(room.get_player(&alice).unwrap().ws.is_none());
+}
+
+#[tokio::test]
+async fn connect_failure_is_a_fatal_error() {
+    let server = TestServer::start().await;
+
+    let mut client = server.connect("not-a-token").await;
+    let error = client.recv_type("Error").await;
+
+    assert_eq!(error["data"]["fatal"], true);
+}
+
+#[tokio::test]
+async fn bad_message_is_not_a_fatal_error() {
+    let server = TestServer::start().await;
+    let (_, token) = server.create_room("alice").await;
+    let mut alice = server.connect(&token).await;
+    alice.recv_type("Sync").await;
+
+    alice
+        .send(json!({ "type": "NoSuchMessage", "ack": "x" }))
+        .await;
+    let error = alice.recv_type("Error").await;
+
+    assert_eq!(error["ack"], "x");
+    assert_eq!(error["data"]["fatal"], false);
+}
+
+#[tokio::test]
+async fn retried_message_is_answered_without_running_again() {
+    let server = TestServer::start().await;
+    let (_, token) = server.create_room("alice").await;
+    let mut alice = server.connect(&token).await;
+    alice.recv_type("Sync").await;
+
+    alice
+        .send(json!({ "type": "Add", "data": [1, 2], "id": "m1", "ack": "a1" }))
+        .await;
+    assert_eq!(alice.recv().await["type"], "AddResult");
+    assert_eq!(alice.recv().await, json!({ "type": "Noop", "ack": "a1" }));
+
+    alice
+        .send(json!({ "type": "Add", "data": [1, 2], "id": "m1", "ack": "a2" }))
+        .await;
+    alice
+        .send(json!({ "type": "Heartbeat", "ack": "a3" }))
+        .await;
+
+    // The retry is answered with its own ack and nothing is broadcast again
+    assert_eq!(alice.recv().await, json!({ "type": "Noop", "ack": "a2" }));
+    assert_eq!(alice.recv().await, json!({ "type": "Noop", "ack": "a3" }));
+}
+
+#[tokio::test]
+async fn distinct_messages_each_run() {
+    let server = TestServer::start().await;
+    let (_, token) = server.create_room("alice").await;
+    let mut alice = server.connect(&token).await;
+    alice.recv_type("Sync").await;
+
+    alice
+        .send(json!({ "type": "Add", "data": [1, 2], "id": "m1", "ack": "a1" }))
+        .await;
+    alice
+        .send(json!({ "type": "Add", "data": [3, 4], "id": "m2", "ack": "a2" }))
+        .await;
+    // Reusing an id for a different message doesn't get the old answer
+    alice
+        .send(json!({ "type": "Add", "data": [5, 6], "id": "m1", "ack": "a3" }))
+        .await;
+
+    for (result, ack) in [(3, "a1"), (7, "a2"), (11, "a3")] {
+        assert_eq!(
+            alice.recv().await,
+            json!({ "type": "AddResult", "data": { "result": result, "username": "alice" } })
+        );
+        assert_eq!(alice.recv().await, json!({ "type": "Noop", "ack": ack }));
+    }
+}
+
+#[test]
+fn fresh_connection_ids_are_distinct() {
+    let first = ConnectionId::generate();
+    let second = ConnectionId::generate();
+
+    assert_ne!(first, second);
+    // The sequence prefix keeps them sortable by creation
+    assert!(first.to_string() < second.to_string());
+}
+
+#[tokio::test]
+async fn fatal_error_closes_with_its_code_and_reason() {
+    let server = TestServer::start().await;
+
+    let mut client = server.connect("not-a-token").await;
+    let error = client.recv_type("Error").await;
+    assert_eq!(error["data"]["code"], 401);
+
+    match client.recv_frame().await {
+        TungsteniteMessage::Close(Some(frame)) => {
+            assert_eq!(u16::from(frame.code), 4401);
+            assert_eq!(frame.reason, error["data"]["message"].as_str().unwrap());
+        }
+        frame => panic!("expected a close frame, got {:?}", frame),
+    }
+}
@@ -0,0 +1,127 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicU64, AtomicUsize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::sync::oneshot::error::TryRecvError;
+use tokio::time::{sleep_until, Instant};
+
+const LOG_TARGET: &str = "muuzika::timers";
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+struct Timer {
+    deadline: Instant,
+    sequence: u64,
+    cancel: oneshot::Receiver<()>,
+    on_fire: Callback,
+}
+
+// Reversed, so the max-heap pops the earliest deadline first (and the earliest
+// scheduled one among equal deadlines)
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deadline, other.sequence).cmp(&(self.deadline, self.sequence))
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        (self.deadline, self.sequence) == (other.deadline, other.sequence)
+    }
+}
+
+impl Eq for Timer {}
+
+/// Deadlines serviced by a few long-lived tasks, instead of one sleeping task per timer.
+///
+/// Cancellation works like a plain `timeout` on a oneshot: sending on (or dropping) the
+/// sender paired with `cancel` before the deadline keeps `on_fire` from running.
+/// `on_fire` runs on the timer task itself, so anything slow has to be spawned off.
+#[derive(Clone)]
+pub struct Timers {
+    shards: Arc<Vec<UnboundedSender<Timer>>>,
+    next_shard: Arc<AtomicUsize>,
+}
+
+impl Timers {
+    pub fn new(shards: usize) -> Self {
+        let shards = (0..shards.max(1))
+            .map(|_| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(run_shard(rx));
+                tx
+            })
+            .collect();
+
+        Self {
+            shards: Arc::new(shards),
+            next_shard: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn schedule<F>(&self, duration: Duration, cancel: oneshot::Receiver<()>, on_fire: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+        let timer = Timer {
+            deadline: Instant::now() + duration,
+            sequence: SEQUENCE.fetch_add(1, atomic::Ordering::Relaxed),
+            cancel,
+            on_fire: Box::new(on_fire),
+        };
+
+        let shard = self.next_shard.fetch_add(1, atomic::Ordering::Relaxed) % self.shards.len();
+        if self.shards[shard].send(timer).is_err() {
+            log::error!(target: LOG_TARGET, "Timer shard {} is gone, dropping timer", shard);
+        }
+    }
+}
+
+async fn run_shard(mut rx: UnboundedReceiver<Timer>) {
+    let mut timers = BinaryHeap::new();
+
+    loop {
+        let next_deadline = timers.peek().map(|timer: &Timer| timer.deadline);
+
+        tokio::select! {
+            timer = rx.recv() => match timer {
+                Some(timer) => timers.push(timer),
+                None => break,
+            },
+            _ = sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                let now = Instant::now();
+                while timers.peek().is_some_and(|timer| timer.deadline <= now) {
+                    if let Some(timer) = timers.pop() {
+                        fire(timer);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn fire(mut timer: Timer) {
+    // Anything but an empty channel means the sender was used or dropped, i.e. cancelled
+    if !matches!(timer.cancel.try_recv(), Err(TryRecvError::Empty)) {
+        return;
+    }
+
+    // Dropping the receiver before running, so the sender sees the timer as done
+    drop(timer.cancel);
+    if catch_unwind(AssertUnwindSafe(timer.on_fire)).is_err() {
+        log::error!(target: LOG_TARGET, "Timer callback panicked");
+    }
+}
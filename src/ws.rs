@@ -1,71 +1,123 @@
+use std::collections::VecDeque;
 use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use derive_more::Display;
 
 use futures_util::stream::SplitStream;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
 use nanoid::nanoid;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::sync::mpsc;
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{mpsc, oneshot, Notify};
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use warp::ws::{Message, WebSocket};
+use warp::ws::Message;
 use warp::{Rejection, Reply};
 
-use crate::errors::MuuzikaError;
+use crate::errors::{ErrorResponse, MuuzikaError};
+use crate::helpers::spawn_supervised;
 use crate::lobby;
 use crate::messages::{handle_client_message, ClientMessage, ServerMessage};
-use crate::rooms::Username;
+use crate::rooms::{ClientMetadata, Username};
 use crate::state::{State, WrappedRoom};
 
-const WS_LOG_TARGET: &'static str = "muuzika::ws";
+const WS_LOG_TARGET: &str = "muuzika::ws";
 
-fn split_and_spawn_flusher(ws: WebSocket) -> (WsConnection, SplitStream<WebSocket>) {
+// The receiver fires if writing to the socket fails, the read side of a dead socket
+// can take a long time to notice, so the connection is dropped right away instead
+fn split_and_spawn_flusher<S, E>(ws: S) -> (WsConnection, SplitStream<S>, oneshot::Receiver<()>)
+where
+    S: Stream<Item = Result<Message, E>> + Sink<Message, Error = E> + Send + 'static,
+    E: fmt::Debug,
+{
     let (mut user_ws_tx, user_ws_rx) = ws.split();
+    let (write_failed_tx, write_failed_rx) = oneshot::channel();
     let (tx, rx) = mpsc::unbounded_channel::<Message>();
     let mut rx = UnboundedReceiverStream::new(rx);
 
-    tokio::task::spawn(async move {
+    let conn = WsConnection {
+        id: ConnectionId::generate(),
+        tx,
+        cancelled: Arc::new(Notify::new()),
+    };
+
+    spawn_supervised(format!("flusher of {:?}", conn), async move {
         while let Some(message) = rx.next().await {
-            user_ws_tx.send(message).await.unwrap_or_else(|e| {
+            if let Err(e) = user_ws_tx.send(message).await {
                 log::debug!(target: WS_LOG_TARGET, "WebSocket send error: {:?}", e);
-            })
+                let _ = write_failed_tx.send(());
+                break;
+            }
         }
     });
 
-    let conn = WsConnection { id: nanoid!(), tx };
-
-    (conn, user_ws_rx)
+    (conn, user_ws_rx, write_failed_rx)
 }
 
 #[derive(Deserialize)]
 pub struct WsQuery {
     pub token: String,
+    pub nonce: Option<String>,
 }
 
 pub async fn handle_ws(
     ws: warp::ws::Ws,
     state: State,
     query: WsQuery,
+    metadata: ClientMetadata,
 ) -> Result<impl Reply, Rejection> {
-    Ok(ws.on_upgrade(move |socket| handle_ws_upgrade(socket, state, query.token)))
+    Ok(ws.on_upgrade(move |socket| handle_ws_upgrade(socket, state, query, metadata)))
 }
 
-pub async fn handle_ws_upgrade(ws: WebSocket, state: State, token: String) {
-    let (conn, mut rx) = split_and_spawn_flusher(ws);
+pub async fn handle_ws_upgrade<S, E>(ws: S, state: State, query: WsQuery, metadata: ClientMetadata)
+where
+    S: Stream<Item = Result<Message, E>> + Sink<Message, Error = E> + Send + 'static,
+    E: fmt::Debug,
+{
+    let (conn, mut rx, mut write_failed) = split_and_spawn_flusher(ws);
+    let mut recent_messages = RecentMessages::new(state.config.message_dedupe_window);
 
-    let (room, username) = match lobby::connect_player(&state, &token, &conn).await {
+    let (room, username) = match lobby::connect_player(
+        &state,
+        &query.token,
+        query.nonce.as_deref(),
+        &conn,
+        &metadata,
+    )
+    .await
+    {
         Ok((room, sync)) => {
             let username = sync.you.clone();
             conn.send(ServerMessage::Sync(sync), None);
             (room, username)
         }
         Err(e) => {
-            conn.send_and_close(ServerMessage::Error(e.into()));
+            conn.send_and_close(e);
             return;
         }
     };
 
-    while let Some(result) = rx.next().await {
+    loop {
+        let result = tokio::select! {
+            result = rx.next() => result,
+            // Only a sent signal counts, the sender is also dropped when the flusher just ends
+            Ok(()) = &mut write_failed => {
+                log::debug!(target: WS_LOG_TARGET, "{:?} | {:?} | Writing to the socket failed, disconnecting", conn, username);
+                break;
+            }
+            _ = conn.cancelled.notified() => {
+                log::debug!(target: WS_LOG_TARGET, "{:?} | {:?} | Connection cancelled by the server, disconnecting", conn, username);
+                break;
+            }
+        };
+        let result = match result {
+            Some(result) => result,
+            None => break,
+        };
+
         let message = match result {
             Ok(m) => m,
             Err(e) => {
@@ -74,43 +126,147 @@ pub async fn handle_ws_upgrade(ws: WebSocket, state: State, token: String) {
             }
         };
         if let Ok(m) = message.to_str() {
-            handle_text_message(&conn, &room, &username, m).await;
+            handle_text_message(&conn, &room, &username, &mut recent_messages, m).await;
         }
     }
 
     let _ = lobby::disconnect_player(&state, &room, &username, &conn).await;
 }
 
-fn parse_message(message: &str) -> (serde_json::Result<ClientMessage>, Option<String>) {
+struct ParsedMessage {
+    message: serde_json::Result<ClientMessage>,
+    ack: Option<String>,
+    id: Option<String>,
+    // The message without its ack and id, what a retry has to repeat to be deduped
+    payload: Value,
+}
+
+fn parse_message(message: &str) -> ParsedMessage {
     let value = match serde_json::from_str::<Value>(message) {
         Ok(v) => v,
         Err(e) => {
-            return (Err(e), None);
+            return ParsedMessage {
+                message: Err(e),
+                ack: None,
+                id: None,
+                payload: Value::Null,
+            };
         }
     };
 
-    let ack = value
-        .get("ack")
-        .map(Value::as_str)
-        .flatten()
-        .map(String::from);
+    let ack = string_field(&value, "ack");
+    let id = string_field(&value, "id");
+    let mut payload = value.clone();
+    if let Value::Object(map) = &mut payload {
+        map.remove("ack");
+        map.remove("id");
+    }
+    ParsedMessage {
+        message: serde_json::from_value::<ClientMessage>(value),
+        ack,
+        id,
+        payload,
+    }
+}
+
+// Acks and ids are always handled as strings, numeric ones are converted so clients
+// using counters still work, anything else is ignored
+fn string_field(value: &Value, key: &str) -> Option<String> {
+    match value.get(key) {
+        Some(Value::String(field)) => Some(field.clone()),
+        Some(Value::Number(field)) => Some(field.to_string()),
+        _ => None,
+    }
+}
+
+// Results of the latest messages sent with an `id`, so a client retrying after a timeout
+// gets the original answer again instead of the message running twice. A reused id with
+// a different payload is a new message, and errors aren't kept so a retry runs again
+struct RecentMessages {
+    window: Duration,
+    entries: VecDeque<RecentMessage>,
+}
 
-    (serde_json::from_value::<ClientMessage>(value), ack)
+struct RecentMessage {
+    id: String,
+    payload: Value,
+    received_at: Instant,
+    result: ServerMessage,
+}
+
+impl RecentMessages {
+    const MAX_ENTRIES: usize = 64;
+
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn evict_expired(&mut self) {
+        while self
+            .entries
+            .front()
+            .is_some_and(|entry| entry.received_at.elapsed() > self.window)
+        {
+            self.entries.pop_front();
+        }
+    }
+
+    fn get(&mut self, id: &str, payload: &Value) -> Option<ServerMessage> {
+        self.evict_expired();
+        self.entries
+            .iter()
+            .find(|entry| entry.id == id && &entry.payload == payload)
+            .map(|entry| entry.result.clone())
+    }
+
+    fn insert(&mut self, id: String, payload: Value, result: ServerMessage) {
+        self.evict_expired();
+        self.entries.retain(|entry| entry.id != id);
+        if self.entries.len() >= Self::MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(RecentMessage {
+            id,
+            payload,
+            received_at: Instant::now(),
+            result,
+        });
+    }
 }
 
 async fn handle_text_message(
     conn: &WsConnection,
     room: &WrappedRoom,
     username: &Username,
+    recent_messages: &mut RecentMessages,
     message: &str,
 ) {
-    const LOG_TARGET: &'static str = "muuzika::ws::handle_text_message";
+    const LOG_TARGET: &str = "muuzika::ws::handle_text_message";
 
     log::trace!(target: LOG_TARGET, "{:?} | {:?} | Received message: {}", conn, username, message);
 
-    let (client_message, ack) = match parse_message(message) {
-        (Ok(m), ack) => (m, ack),
-        (Err(e), ack) => {
+    let ParsedMessage {
+        message,
+        ack,
+        id,
+        payload,
+    } = parse_message(message);
+
+    if let Some(result) = id
+        .as_deref()
+        .and_then(|id| recent_messages.get(id, &payload))
+    {
+        log::debug!(target: LOG_TARGET, "{:?} | {:?} | Message {:?} was already handled, answering again", conn, username, id);
+        conn.send(result, ack);
+        return;
+    }
+
+    let client_message = match message {
+        Ok(m) => m,
+        Err(e) => {
             log::debug!(target: LOG_TARGET, "{:?} | {:?} | Error parsing message: {:?}", conn, username, e);
             conn.send(ServerMessage::Error(MuuzikaError::from(e).into()), ack);
             return;
@@ -118,9 +274,12 @@ async fn handle_text_message(
     };
 
     log::trace!(target: LOG_TARGET, "{:?} | {:?} | Handling message: {:?}", conn, username, client_message);
-    let result = handle_client_message(client_message, username, room).await;
+    let result = handle_client_message(client_message, conn, username, room).await;
     log::trace!(target: LOG_TARGET, "{:?} | {:?} | Answering with: {:?}, ack={:?}", conn, username, result, ack);
 
+    if let Some(id) = id.filter(|_| !matches!(result, ServerMessage::Error(_))) {
+        recent_messages.insert(id, payload, result.clone());
+    }
     conn.send(result, ack);
 }
 
@@ -143,17 +302,44 @@ where
     Ok(Message::text(text))
 }
 
+// The sequence makes ids sortable by connection order, which helps when reading logs,
+// and also rules out collisions between ids from the same process
+#[derive(Serialize, Display, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ConnectionId(String);
+
+impl ConnectionId {
+    pub fn generate() -> Self {
+        static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        Self(format!("{:06}-{}", sequence, nanoid!(8)))
+    }
+}
+
 #[derive(Clone)]
 pub struct WsConnection {
-    pub id: String,
+    pub id: ConnectionId,
     pub tx: UnboundedSender<Message>,
+    // Stored as a permit, so a cancel before the read loop starts waiting isn't lost
+    pub cancelled: Arc<Notify>,
 }
 
 impl WsConnection {
+    // Ends the read loop without waiting on the client, which then disconnects the
+    // player just like a closed socket would
+    pub fn cancel(&self) {
+        self.cancelled.notify_one();
+    }
+
     pub fn close(&self) {
         let _ = self.tx.send(Message::close());
     }
 
+    pub fn close_with_reason(&self, code: u16, reason: String) {
+        let _ = self
+            .tx
+            .send(Message::close_with(code, truncate_close_reason(reason)));
+    }
+
     pub fn send_raw(&self, message: Message) -> bool {
         self.tx.send(message).is_ok()
     }
@@ -170,19 +356,37 @@ impl WsConnection {
         }
     }
 
-    pub fn send_and_close<T>(&self, message: T)
-    where
-        T: serde::Serialize,
-    {
-        if self.send(message, None) {
-            self.close();
+    pub fn send_and_close(&self, error: MuuzikaError) {
+        let code = error.close_code();
+        let reason = error.to_string();
+        let response = ErrorResponse::from(&error).fatal();
+        if self.send(ServerMessage::Error(response), None) {
+            self.close_with_reason(code, reason);
+        }
+        self.cancel();
+    }
+}
+
+// Close frames have to fit in a 125 bytes control frame, 2 of which are the code
+fn truncate_close_reason(mut reason: String) -> String {
+    const MAX_LENGTH: usize = 123;
+
+    if reason.len() > MAX_LENGTH {
+        let mut end = MAX_LENGTH;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
         }
+        reason.truncate(end);
     }
+
+    reason
 }
 
 impl fmt::Debug for WsConnection {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_tuple("WsConnection").field(&self.id).finish()
+        f.debug_tuple("WsConnection")
+            .field(&format_args!("{}", self.id))
+            .finish()
     }
 }
 